@@ -0,0 +1,63 @@
+// Compares the checked `Machine` against the pre-decoded `CompiledProgram` on a long-running
+// program. Run with `cargo bench --bench vm`.
+use advent_of_code_2018::day16::assembler::assemble;
+use advent_of_code_2018::day16::compiled::CompiledProgram;
+use advent_of_code_2018::day16::executor::Machine;
+use advent_of_code_2018::day16::{Overflow, Register};
+use std::time::{Duration, Instant};
+
+// Sums the divisors of r4 by trying every pair of factors, like the day 19 puzzle.
+const DIVISOR_SUM : &str = "#ip 5
+                            seti 1 0 1
+                            seti 1 0 2
+                            mulr 1 2 3
+                            eqrr 3 4 3
+                            addr 3 5 5
+                            addi 5 1 5
+                            addr 1 0 0
+                            addi 2 1 2
+                            gtrr 2 4 3
+                            addr 5 3 5
+                            seti 1 0 5
+                            addi 1 1 1
+                            gtrr 1 4 3
+                            addr 3 5 5
+                            seti 0 0 5
+                            seti 99 0 5";
+
+fn time<F : FnMut() -> i64>(runs : u32, mut run : F) -> (Duration, i64) {
+    let mut result = 0;
+    let start = Instant::now();
+    for _ in 0..runs {
+        result = run();
+    }
+    (start.elapsed() / runs, result)
+}
+
+fn main() {
+    let program = assemble(DIVISOR_SUM).unwrap();
+    let compiled = CompiledProgram::compile(&program, 6).unwrap();
+    let initial = Register::from("0, 0, 0, 0, 1000, 0");
+    let runs = 5;
+
+    let mut register = initial.clone();
+    let steps = compiled.run(&mut register, Overflow::Checked).unwrap();
+
+    let (checked, expected) = time(runs, ||{
+        let mut machine = Machine::new(&program, initial.clone());
+        machine.run().unwrap().get(0).unwrap()
+    });
+
+    let (fast, result) = time(runs, ||{
+        let mut register = initial.clone();
+        compiled.run(&mut register, Overflow::Checked).unwrap();
+        register.get(0).unwrap()
+    });
+
+    assert_eq!(result, expected);
+
+    println!("{} instructions per run, average of {} runs", steps, runs);
+    println!("machine:  {:>10.2?} ({:.1} ns/instruction)", checked, checked.as_nanos() as f64 / steps as f64);
+    println!("compiled: {:>10.2?} ({:.1} ns/instruction)", fast, fast.as_nanos() as f64 / steps as f64);
+    println!("speedup:  {:.2}x", checked.as_secs_f64() / fast.as_secs_f64());
+}
@@ -0,0 +1,41 @@
+extern crate advent_of_code_2018;
+
+use advent_of_code_2018::day16::assembler::assemble;
+use advent_of_code_2018::day16::debugger::{repl, Debugger};
+use advent_of_code_2018::day16::Register;
+use std::io;
+use std::process;
+
+fn main() {
+    let args : Vec<String> = std::env::args().collect();
+
+    if args.len() < 2 || args.len() > 3 {
+        eprintln!("usage: {} <program> [register count]", args[0]);
+        process::exit(2);
+    }
+
+    let source = std::fs::read_to_string(&args[1]).unwrap_or_else(|error|{
+        eprintln!("{}: {}", args[1], error);
+        process::exit(1);
+    });
+
+    let program = assemble(&source).unwrap_or_else(|error|{
+        eprintln!("{}:{}", args[1], error);
+        process::exit(1);
+    });
+
+    let register_count = match args.get(2) {
+        Some(count) => count.parse().unwrap_or_else(|_|{
+            eprintln!("register count must be a number, found `{}`", count);
+            process::exit(1);
+        }),
+        None => 6,
+    };
+
+    let mut debugger = Debugger::new(&program, Register::new(register_count));
+    let stdin = io::stdin();
+    if let Err(error) = repl(&mut debugger, stdin.lock(), &mut io::stdout()) {
+        eprintln!("{}", error);
+        process::exit(1);
+    }
+}
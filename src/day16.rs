@@ -1,26 +1,129 @@
-use regex::{Regex};
 use std::fmt;
-use std::collections::HashSet;
+use std::error::Error;
+use opcode_set::OpcodeSet;
+
+pub mod assembler;
+pub mod bytecode;
+pub mod compiled;
+pub mod control_flow;
+pub mod debugger;
+pub mod decompiler;
+pub mod disassembler;
+pub mod executor;
+pub mod generator;
+pub mod opcode_set;
+pub mod parser;
+pub mod registry;
+pub mod solver;
+pub mod trace;
+pub mod typed;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Register (Vec<i64>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterError {
+    pub register : i64,
+    pub size : usize,
+}
+
+impl fmt::Display for RegisterError {
 
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "register {} is out of range for a register file of {}", self.register, self.size)
+    }
+
+}
+
+impl Error for RegisterError {}
+
+// Why a single instruction could not be applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApplyError {
+    Register(RegisterError),
+    Overflow,
+    // A registered operation has no result for its operands, such as a division by zero.
+    Undefined,
+    // A custom opcode applied without the registry that defines it.
+    UnknownOpcode(Opcodes),
+}
+
+impl From<RegisterError> for ApplyError {
+    fn from(error : RegisterError) -> ApplyError {
+        ApplyError::Register(error)
+    }
+}
 
-#[derive(Debug, Clone, PartialEq)]
-struct Register (i64, i64, i64, i64);
+impl fmt::Display for ApplyError {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApplyError::Register(error) => write!(f, "{}", error),
+            ApplyError::Overflow => write!(f, "arithmetic overflow"),
+            ApplyError::Undefined => write!(f, "the result is undefined for these operands"),
+            ApplyError::UnknownOpcode(opcode) => write!(f, "opcode {} is not registered", opcode),
+        }
+    }
+
+}
+
+impl Error for ApplyError {}
+
+// How addr, addi, mulr and muli treat results that do not fit in an i64, so that they no longer
+// depend on whether overflow checks are compiled in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Overflow {
+    #[default]
+    Checked,
+    Wrapping,
+    Saturating,
+}
+
+impl Overflow {
+    // None when the sum overflows under checked arithmetic.
+    pub fn add(self, a : i64, b : i64) -> Option<i64> {
+        match self {
+            Overflow::Checked => a.checked_add(b),
+            Overflow::Wrapping => Some(a.wrapping_add(b)),
+            Overflow::Saturating => Some(a.saturating_add(b)),
+        }
+    }
+
+    pub fn mul(self, a : i64, b : i64) -> Option<i64> {
+        match self {
+            Overflow::Checked => a.checked_mul(b),
+            Overflow::Wrapping => Some(a.wrapping_mul(b)),
+            Overflow::Saturating => Some(a.saturating_mul(b)),
+        }
+    }
+
+    pub fn sub(self, a : i64, b : i64) -> Option<i64> {
+        match self {
+            Overflow::Checked => a.checked_sub(b),
+            Overflow::Wrapping => Some(a.wrapping_sub(b)),
+            Overflow::Saturating => Some(a.saturating_sub(b)),
+        }
+    }
+}
 
 trait IntoRegister {
-    fn into_register(&self) -> Register;
+    fn into_register(self) -> Register;
 }
 
 
 impl IntoRegister for &[i64] {
-    fn into_register(&self) -> Register {
-        Register(self[0], self[1], self[2], self[3])
+    fn into_register(self) -> Register {
+        Register(self.to_vec())
     }
 }
 
 impl fmt::Display for Register {
 
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "[{}, {}, {}, {}]", self.0, self.1, self.2, self.3)
+        let values : Vec<String> = self.0.iter().map(|x| x.to_string()).collect();
+        write!(f, "[{}]", values.join(", "))
     }
 
 }
@@ -39,36 +142,72 @@ impl From<&str> for Register {
 }
 
 impl Register {
-    fn reference(&self, idx : i64) -> &i64 {
-        match idx {
-            0 => &self.0,
-            1 => &self.1,
-            2 => &self.2,
-            3 => &self.3,
-            _ => panic!("Reference is out of register range.")
+    pub fn new(size : usize) -> Register {
+        Register(vec![0; size])
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn values(&self) -> &[i64] {
+        &self.0
+    }
+
+    fn reference(&self, idx : i64) -> Result<&i64, RegisterError> {
+        let size = self.0.len();
+
+        if idx < 0 {
+            return Err(RegisterError { register: idx, size });
         }
+
+        self.0.get(idx as usize).ok_or(RegisterError { register: idx, size })
     }
 
-    fn reference_mut(&mut self, idx : i64) -> &mut i64 {
-        match idx {
-            0 => &mut self.0,
-            1 => &mut self.1,
-            2 => &mut self.2,
-            3 => &mut self.3,
-            _ => panic!("Reference is out of register range.")
+    fn reference_mut(&mut self, idx : i64) -> Result<&mut i64, RegisterError> {
+        let size = self.0.len();
+
+        if idx < 0 {
+            return Err(RegisterError { register: idx, size });
         }
+
+        self.0.get_mut(idx as usize).ok_or(RegisterError { register: idx, size })
+    }
+
+    pub fn get(&self, idx : usize) -> Option<i64> {
+        self.0.get(idx).copied()
+    }
+
+    pub fn set(&mut self, idx : usize, value : i64) -> Result<(), RegisterError> {
+        *self.reference_mut(idx as i64)? = value;
+        Ok(())
+    }
+
+    // Reads a register operand as written in an instruction, for implementing custom operations.
+    pub fn read(&self, operand : i64) -> Result<i64, RegisterError> {
+        self.reference(operand).copied()
+    }
+
+    pub fn write(&mut self, operand : i64, value : i64) -> Result<(), RegisterError> {
+        *self.reference_mut(operand)? = value;
+        Ok(())
     }
 }
 
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct InstructionSet(i64, i64, i64, i64);
 trait IntoInstruction {
-    fn into_instruction(&self) -> InstructionSet;
+    fn into_instruction(self) -> InstructionSet;
 }
 
 impl IntoInstruction for &[i64] {
-    fn into_instruction(&self) -> InstructionSet {
+    fn into_instruction(self) -> InstructionSet {
         InstructionSet(self[0], self[1], self[2], self[3])
     }
 }
@@ -94,7 +233,8 @@ impl From<&str> for InstructionSet {
     }
 }
 
-
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TestCase
 {
     before_register : Register,
@@ -102,9 +242,16 @@ pub struct TestCase
     after_register : Register,
 }
 
+impl TestCase {
+    fn matching_opcodes(&self) -> OpcodeSet {
+        registry::OpcodeRegistry::builtin().matching_opcodes(self)
+    }
+}
+
 #[allow(non_camel_case_types)]
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
-enum Opcodes {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Opcodes {
     addr,
     addi,
     mulr,
@@ -120,121 +267,302 @@ enum Opcodes {
     gtrr,
     eqir,
     eqri,
-    eqrr,    
+    eqrr,
+    // An operation added through an `OpcodeRegistry`, numbered in registration order.
+    Custom(u8),
 }
 
-fn addr(instruction: &InstructionSet, register : &mut Register) {
-    *register.reference_mut(instruction.3) = register.reference(instruction.1) + register.reference(instruction.2);
+fn addr(instruction: &InstructionSet, register : &mut Register, overflow : Overflow) -> Result<(), ApplyError> {
+    let value = overflow.add(*register.reference(instruction.1)?, *register.reference(instruction.2)?).ok_or(ApplyError::Overflow)?;
+    *register.reference_mut(instruction.3)? = value;
+    Ok(())
 }
-fn addi(instruction: &InstructionSet, register : &mut Register) {
-    *register.reference_mut(instruction.3) = register.reference(instruction.1) + instruction.2;
+fn addi(instruction: &InstructionSet, register : &mut Register, overflow : Overflow) -> Result<(), ApplyError> {
+    let value = overflow.add(*register.reference(instruction.1)?, instruction.2).ok_or(ApplyError::Overflow)?;
+    *register.reference_mut(instruction.3)? = value;
+    Ok(())
 }
-fn mulr(instruction: &InstructionSet, register : &mut Register) {
-    *register.reference_mut(instruction.3) = register.reference(instruction.1) * register.reference(instruction.2);
+fn mulr(instruction: &InstructionSet, register : &mut Register, overflow : Overflow) -> Result<(), ApplyError> {
+    let value = overflow.mul(*register.reference(instruction.1)?, *register.reference(instruction.2)?).ok_or(ApplyError::Overflow)?;
+    *register.reference_mut(instruction.3)? = value;
+    Ok(())
 }
-fn muli(instruction: &InstructionSet, register : &mut Register) {
-    *register.reference_mut(instruction.3) = register.reference(instruction.1) * instruction.2;
+fn muli(instruction: &InstructionSet, register : &mut Register, overflow : Overflow) -> Result<(), ApplyError> {
+    let value = overflow.mul(*register.reference(instruction.1)?, instruction.2).ok_or(ApplyError::Overflow)?;
+    *register.reference_mut(instruction.3)? = value;
+    Ok(())
 }
-fn banr(instruction: &InstructionSet, register : &mut Register) {
-    *register.reference_mut(instruction.3) = register.reference(instruction.1) & register.reference(instruction.2);
+fn banr(instruction: &InstructionSet, register : &mut Register) -> Result<(), ApplyError> {
+    *register.reference_mut(instruction.3)? = register.reference(instruction.1)? & register.reference(instruction.2)?;
+    Ok(())
 }
-fn bani(instruction: &InstructionSet, register : &mut Register) {
-    *register.reference_mut(instruction.3) = register.reference(instruction.1) & instruction.2;
+fn bani(instruction: &InstructionSet, register : &mut Register) -> Result<(), ApplyError> {
+    *register.reference_mut(instruction.3)? = register.reference(instruction.1)? & instruction.2;
+    Ok(())
 }
-fn borr(instruction: &InstructionSet, register : &mut Register) {
-    *register.reference_mut(instruction.3) = register.reference(instruction.1) | register.reference(instruction.2);
+fn borr(instruction: &InstructionSet, register : &mut Register) -> Result<(), ApplyError> {
+    *register.reference_mut(instruction.3)? = register.reference(instruction.1)? | register.reference(instruction.2)?;
+    Ok(())
 }
-fn bori(instruction: &InstructionSet, register : &mut Register) {
-    *register.reference_mut(instruction.3) = register.reference(instruction.1) | instruction.2;
+fn bori(instruction: &InstructionSet, register : &mut Register) -> Result<(), ApplyError> {
+    *register.reference_mut(instruction.3)? = register.reference(instruction.1)? | instruction.2;
+    Ok(())
 }
-fn setr(instruction: &InstructionSet, register : &mut Register) {
-    *register.reference_mut(instruction.3) = *register.reference(instruction.1);
+fn setr(instruction: &InstructionSet, register : &mut Register) -> Result<(), ApplyError> {
+    *register.reference_mut(instruction.3)? = *register.reference(instruction.1)?;
+    Ok(())
 }
-fn seti(instruction: &InstructionSet, register : &mut Register) {
-    *register.reference_mut(instruction.3) = instruction.1;
+fn seti(instruction: &InstructionSet, register : &mut Register) -> Result<(), ApplyError> {
+    *register.reference_mut(instruction.3)? = instruction.1;
+    Ok(())
 }
-fn gtir(instruction: &InstructionSet, register : &mut Register) {
-    if instruction.1 > *register.reference(instruction.2)
+fn gtir(instruction: &InstructionSet, register : &mut Register) -> Result<(), ApplyError> {
+    if instruction.1 > *register.reference(instruction.2)?
     {
-        *register.reference_mut(instruction.3) = 1;
+        *register.reference_mut(instruction.3)? = 1;
     }
     else
     {
-        *register.reference_mut(instruction.3) = 0;
+        *register.reference_mut(instruction.3)? = 0;
     }
+    Ok(())
 }
-fn gtri(instruction: &InstructionSet, register : &mut Register) {
-    if *register.reference(instruction.1) > instruction.2
+fn gtri(instruction: &InstructionSet, register : &mut Register) -> Result<(), ApplyError> {
+    if *register.reference(instruction.1)? > instruction.2
     {
-        *register.reference_mut(instruction.3) = 1;
+        *register.reference_mut(instruction.3)? = 1;
     }
     else
     {
-        *register.reference_mut(instruction.3) = 0;
+        *register.reference_mut(instruction.3)? = 0;
     }
+    Ok(())
 }
-fn gtrr(instruction: &InstructionSet, register : &mut Register) {
-    if *register.reference(instruction.1) > *register.reference(instruction.2)
+fn gtrr(instruction: &InstructionSet, register : &mut Register) -> Result<(), ApplyError> {
+    if *register.reference(instruction.1)? > *register.reference(instruction.2)?
     {
-        *register.reference_mut(instruction.3) = 1;
+        *register.reference_mut(instruction.3)? = 1;
     }
     else
     {
-        *register.reference_mut(instruction.3) = 0;
+        *register.reference_mut(instruction.3)? = 0;
     }
+    Ok(())
 }
-fn eqir(instruction: &InstructionSet, register : &mut Register) {
-    if instruction.1 == *register.reference(instruction.2)
+fn eqir(instruction: &InstructionSet, register : &mut Register) -> Result<(), ApplyError> {
+    if instruction.1 == *register.reference(instruction.2)?
     {
-        *register.reference_mut(instruction.3) = 1;
+        *register.reference_mut(instruction.3)? = 1;
     }
     else
     {
-        *register.reference_mut(instruction.3) = 0;
+        *register.reference_mut(instruction.3)? = 0;
     }
+    Ok(())
 }
-fn eqri(instruction: &InstructionSet, register : &mut Register) {
-    if *register.reference(instruction.1) == instruction.2
+fn eqri(instruction: &InstructionSet, register : &mut Register) -> Result<(), ApplyError> {
+    if *register.reference(instruction.1)? == instruction.2
     {
-        *register.reference_mut(instruction.3) = 1;
+        *register.reference_mut(instruction.3)? = 1;
     }
     else
     {
-        *register.reference_mut(instruction.3) = 0;
+        *register.reference_mut(instruction.3)? = 0;
     }
+    Ok(())
 }
-fn eqrr(instruction: &InstructionSet, register : &mut Register) {
-    if *register.reference(instruction.1) == *register.reference(instruction.2)
+fn eqrr(instruction: &InstructionSet, register : &mut Register) -> Result<(), ApplyError> {
+    if *register.reference(instruction.1)? == *register.reference(instruction.2)?
     {
-        *register.reference_mut(instruction.3) = 1;
+        *register.reference_mut(instruction.3)? = 1;
     }
     else
     {
-        *register.reference_mut(instruction.3) = 0;
+        *register.reference_mut(instruction.3)? = 0;
+    }
+    Ok(())
+}
+
+// How an instruction uses its a or b operand. Operand c is always the register written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Mode {
+    Register,
+    Immediate,
+    Ignored,
+}
+
+impl Mode {
+    pub fn is_register(self) -> bool {
+        self == Mode::Register
     }
 }
 
 impl Opcodes {
-    fn apply(&self, instruction: &InstructionSet, register : &mut Register) {
+    // The modes of operands a and b. A custom opcode's modes are only known to its registry
+    // (see `OpcodeRegistry::modes`), so here both are assumed to be registers.
+    pub fn modes(self) -> (Mode, Mode) {
+        match self {
+            Opcodes::addr | Opcodes::mulr | Opcodes::banr | Opcodes::borr | Opcodes::gtrr | Opcodes::eqrr => (Mode::Register, Mode::Register),
+            Opcodes::addi | Opcodes::muli | Opcodes::bani | Opcodes::bori | Opcodes::gtri | Opcodes::eqri => (Mode::Register, Mode::Immediate),
+            Opcodes::gtir | Opcodes::eqir => (Mode::Immediate, Mode::Register),
+            Opcodes::setr => (Mode::Register, Mode::Ignored),
+            Opcodes::seti => (Mode::Immediate, Mode::Ignored),
+            Opcodes::Custom(_) => (Mode::Register, Mode::Register),
+        }
+    }
+
+    // Built-ins come first, in the order of POSSIBLE_INSTRUCTIONS, followed by custom opcodes.
+    pub fn index(self) -> usize {
+        match self {
+            Opcodes::addr => 0,
+            Opcodes::addi => 1,
+            Opcodes::mulr => 2,
+            Opcodes::muli => 3,
+            Opcodes::banr => 4,
+            Opcodes::bani => 5,
+            Opcodes::borr => 6,
+            Opcodes::bori => 7,
+            Opcodes::setr => 8,
+            Opcodes::seti => 9,
+            Opcodes::gtir => 10,
+            Opcodes::gtri => 11,
+            Opcodes::gtrr => 12,
+            Opcodes::eqir => 13,
+            Opcodes::eqri => 14,
+            Opcodes::eqrr => 15,
+            Opcodes::Custom(id) => POSSIBLE_INSTRUCTIONS.len() + id as usize,
+        }
+    }
+
+    pub fn from_index(index : usize) -> Opcodes {
+        match POSSIBLE_INSTRUCTIONS.get(index) {
+            Some(opcode) => *opcode,
+            None => Opcodes::Custom((index - POSSIBLE_INSTRUCTIONS.len()) as u8),
+        }
+    }
+
+    pub fn from_name(name : &str) -> Option<Opcodes> {
+        POSSIBLE_INSTRUCTIONS
+            .iter()
+            .find(|opcode| opcode.to_string() == name)
+            .copied()
+    }
+
+    fn apply(&self, instruction: &InstructionSet, register : &mut Register, overflow : Overflow) -> Result<(), ApplyError> {
+        match self {
+            Opcodes::addr => addr(instruction, register, overflow),
+            Opcodes::addi => addi(instruction, register, overflow),
+            Opcodes::mulr => mulr(instruction, register, overflow),
+            Opcodes::muli => muli(instruction, register, overflow),
+            Opcodes::banr => banr(instruction, register),
+            Opcodes::bani => bani(instruction, register),
+            Opcodes::borr => borr(instruction, register),
+            Opcodes::bori => bori(instruction, register),
+            Opcodes::setr => setr(instruction, register),
+            Opcodes::seti => seti(instruction, register),
+            Opcodes::gtir => gtir(instruction, register),
+            Opcodes::gtri => gtri(instruction, register),
+            Opcodes::gtrr => gtrr(instruction, register),
+            Opcodes::eqir => eqir(instruction, register),
+            Opcodes::eqri => eqri(instruction, register),
+            Opcodes::eqrr => eqrr(instruction, register),
+            Opcodes::Custom(_) => Err(ApplyError::UnknownOpcode(*self)),
+        }
+    }
+}
+
+impl fmt::Display for Opcodes {
+
+    // Custom opcodes only have names within their registry; see `OpcodeRegistry::name`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Opcodes::addr => addr(&instruction, register),
-            Opcodes::addi => addi(&instruction, register),
-            Opcodes::mulr => mulr(&instruction, register),
-            Opcodes::muli => muli(&instruction, register),
-            Opcodes::banr => banr(&instruction, register),
-            Opcodes::bani => bani(&instruction, register),
-            Opcodes::borr => borr(&instruction, register),
-            Opcodes::bori => bori(&instruction, register),
-            Opcodes::setr => setr(&instruction, register),
-            Opcodes::seti => seti(&instruction, register),
-            Opcodes::gtir => gtir(&instruction, register),
-            Opcodes::gtri => gtri(&instruction, register),
-            Opcodes::gtrr => gtrr(&instruction, register),
-            Opcodes::eqir => eqir(&instruction, register),
-            Opcodes::eqri => eqri(&instruction, register),
-            Opcodes::eqrr => eqrr(&instruction, register),   
+            Opcodes::Custom(id) => write!(f, "custom{}", id),
+            builtin => write!(f, "{:?}", builtin),
         }
     }
+
+}
+
+// An instruction whose opcode has already been resolved, as produced by the assembler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Instruction {
+    pub opcode : Opcodes,
+    pub a : i64,
+    pub b : i64,
+    pub c : i64,
+}
+
+impl Instruction {
+    pub fn apply(&self, register : &mut Register) -> Result<(), ApplyError> {
+        self.apply_with(register, Overflow::Checked)
+    }
+
+    pub fn apply_with(&self, register : &mut Register, overflow : Overflow) -> Result<(), ApplyError> {
+        // Opcodes::apply never looks at the opcode number, so it is left as 0.
+        self.opcode.apply(&InstructionSet(0, self.a, self.b, self.c), register, overflow)
+    }
+}
+
+impl fmt::Display for Instruction {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {} {}", self.opcode, self.a, self.b, self.c)
+    }
+
+}
+
+// A decoded program, optionally with a register bound to the instruction pointer (`#ip N`).
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Program {
+    pub ip_register : Option<usize>,
+    pub instructions : Vec<Instruction>,
+}
+
+// Maps the opcode numbers used by a numeric program onto the operations they stand for.
+// Custom opcodes in a saved mapping only mean something to the registry that handed them out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OpcodeMapping(Vec<Opcodes>);
+
+impl OpcodeMapping {
+    pub fn new(opcodes : Vec<Opcodes>) -> OpcodeMapping {
+        OpcodeMapping(opcodes)
+    }
+
+    pub fn get(&self, number : i64) -> Option<Opcodes> {
+        if number < 0 {
+            return None;
+        }
+
+        self.0.get(number as usize).copied()
+    }
+
+    pub fn decode(&self, instruction : &InstructionSet) -> Option<Instruction> {
+        self.get(instruction.0)
+            .map(|opcode| Instruction { opcode, a: instruction.1, b: instruction.2, c: instruction.3 })
+    }
+
+    pub fn decode_program(&self, program : &[InstructionSet]) -> Option<Program> {
+        program
+            .iter()
+            .map(|instruction| self.decode(instruction))
+            .collect::<Option<Vec<_>>>()
+            .map(|instructions| Program { ip_register: None, instructions })
+    }
+}
+
+impl fmt::Display for OpcodeMapping {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0
+            .iter()
+            .enumerate()
+            .try_for_each(|(number, opcode)| writeln!(f, "{:>2} => {}", number, opcode))
+    }
+
 }
 
 const POSSIBLE_INSTRUCTIONS : [Opcodes; 16] = [
@@ -259,77 +587,41 @@ const POSSIBLE_INSTRUCTIONS : [Opcodes; 16] = [
 impl fmt::Display for TestCase {
 
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Before: {}\n{}\nAfter:  {} ", self.before_register, self.instruction, self.after_register)
+        write!(f, "Before: {}\n{}\nAfter:  {}", self.before_register, self.instruction, self.after_register)
     }
 
 }
 
+// Both parts read the input the same way, so a malformed input fails both alike.
+fn parse_input(input: &str) -> parser::Day16Input
+{
+    parser::parse(input).unwrap_or_else(|errors| {
+        let errors : Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+        panic!("Malformed day 16 input:\n{}", errors.join("\n"));
+    })
+}
+
 #[aoc_generator(day16, part1)]
 pub fn input_generator_part1(input: &str) -> Vec<TestCase>
 {
-    lazy_static! {
-        static ref TESTCASE_REGEX : Regex = Regex::new(
-r"(?m)Before: \[(?P<before>\d+, \d+, \d+, \d+)\]
-(?P<instruction>\d+ \d+ \d+ \d+)
-After:  \[(?P<after>\d+, \d+, \d+, \d+)\]").unwrap();
-    }
-
-    TESTCASE_REGEX.captures_iter(input)
-                  .map(|cap|{
-                        TestCase { before_register: Register::from(cap.name("before").unwrap().as_str()), instruction: InstructionSet::from(cap.name("instruction").unwrap().as_str()), after_register: Register::from(cap.name("after").unwrap().as_str())}
-                   })
-                   .collect()
+    parse_input(input).samples
 }
 
 #[aoc_generator(day16, part2)]
 pub fn input_generator_part2(input: &str) -> (Vec<TestCase>, Vec<InstructionSet>)
 {
-    lazy_static! {
-        static ref TESTCASE_REGEX : Regex = Regex::new(
-r"(?m)Before: \[(?P<before>\d+, \d+, \d+, \d+)\]
-(?P<instruction>\d+ \d+ \d+ \d+)
-After:  \[(?P<after>\d+, \d+, \d+, \d+)\]").unwrap();
-
-        static ref INSTRUCTION_REGEX : Regex = Regex::new(r"(?P<instruction>\d+ \d+ \d+ \d+)").unwrap();
-    }
-
-    let split_input : Vec<_> = input.split("\n\n\n").collect();
-    assert_eq!(split_input.len(), 2);
-    
-    let test_cases = TESTCASE_REGEX.captures_iter(split_input[0])
-                  .map(|cap|{
-                        TestCase { before_register: Register::from(cap.name("before").unwrap().as_str()), instruction: InstructionSet::from(cap.name("instruction").unwrap().as_str()), after_register: Register::from(cap.name("after").unwrap().as_str())}
-                   })
-                   .collect();
-
-    let instructions = INSTRUCTION_REGEX.captures_iter(split_input[1])
-                        .map(|cap|{
-                             InstructionSet::from(cap.name("instruction").unwrap().as_str())
-                        })
-                        .collect();
-
-
-    (test_cases, instructions)
+    let parsed = parse_input(input);
+    (parsed.samples, parsed.program)
 }
 
 
 #[aoc(day16, part1)]
-pub fn solve_part1(input: &Vec<TestCase>) -> u64 {
+pub fn solve_part1(input: &[TestCase]) -> u64 {
 
     input
         .iter()
         .map(|test_case|{
-            let possible_opcodes : Vec<Opcodes> = POSSIBLE_INSTRUCTIONS
-                .iter()
-                .filter(|opcode|{
-                    let mut register_test = test_case.before_register.clone();
-                    opcode.apply(&test_case.instruction, &mut register_test);
-                    test_case.after_register == register_test
-                })
-                .map(|&op| op)
-                .collect();
-
-            (test_case.instruction.0, possible_opcodes)
+            (test_case.instruction.0, test_case.matching_opcodes())
     })
     .fold(0u64, |acc, (_instruction_code, possible_opcodes)|{
         if possible_opcodes.len() >= 3 {
@@ -343,63 +635,87 @@ pub fn solve_part1(input: &Vec<TestCase>) -> u64 {
 
 #[aoc(day16, part2)]
 pub fn solve_part2((test_cases, program): &(Vec<TestCase>, Vec<InstructionSet>)) -> i64 {
-    
-    let mut instructions_opcode_map = vec![POSSIBLE_INSTRUCTIONS.iter().cloned().collect::<HashSet<Opcodes>>(); 16];
-
-    test_cases
-        .iter()
-        .for_each(|test_case|{
-            let possible_opcodes : HashSet<Opcodes> = POSSIBLE_INSTRUCTIONS
-                .iter()
-                .filter(|opcode|{
-                    let mut register_test = test_case.before_register.clone();
-                    opcode.apply(&test_case.instruction, &mut register_test);
-                    test_case.after_register == register_test
-                })
-                .map(|&op| op)
-                .collect();
-
-            let intersection : HashSet<Opcodes> = instructions_opcode_map[test_case.instruction.0 as usize].intersection(&possible_opcodes).copied().collect();
-            
-            instructions_opcode_map[test_case.instruction.0 as usize] = intersection;
-    });
-
-    let mut instructions_opcode : Vec<Option<Opcodes>> = vec![None; 16];
-    
-    while instructions_opcode.iter().any(|x| x.is_none()) {
-        let single_instructions : Vec<_>= instructions_opcode_map
-                                        .iter()
-                                        .enumerate()
-                                        .filter(|(_, x)| x.len() == 1 )
-                                        .map(|(i, x)|{
-                                            (i, x.iter().take(1).next().unwrap().clone())
-                                        })
-                                        .collect();
-        
-        single_instructions
-            .iter()
-            .for_each(|(idx, instruction)|{
-                instructions_opcode[*idx].get_or_insert(*instruction);
 
-                instructions_opcode_map
-                        .iter_mut()
-                        .for_each(|x|{
-                            x.remove(instruction);
-                        });
-            });                                            
-    }
+    let mapping = match solver::solve_mapping(test_cases) {
+        solver::MappingSolution::Complete(mapping) => mapping,
+        unsolved => panic!("Could not deduce the opcode mapping: {:?}", unsolved),
+    };
+    let program = mapping.decode_program(program).unwrap();
 
-    let mut program_register = Register(0, 0, 0, 0);
-    program
-        .iter()
-        .for_each(|instruction|{
-            instructions_opcode[instruction.0 as usize].unwrap().apply(instruction, &mut program_register);
-        });
+    let mut machine = executor::Machine::new(&program, Register::new(4));
+    machine.run().unwrap();
 
-    program_register.0
+    machine.register().0[0]
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    #[test]
+    fn register_round_trip_any_width() {
 
-}
\ No newline at end of file
+        let register = Register::from("1, 2, 3, 4, 5, 6");
+
+        assert_eq!(register.len(), 6);
+        assert_eq!(register.to_string(), "[1, 2, 3, 4, 5, 6]");
+        assert_eq!(Register::from("7").to_string(), "[7]");
+        assert_eq!(Register::new(3), Register::from("0, 0, 0"));
+    }
+
+    #[test]
+    fn register_six_wide_operands() {
+
+        let mut register = Register::from("0, 0, 0, 0, 4, 5");
+        Instruction { opcode: Opcodes::mulr, a: 4, b: 5, c: 3 }.apply(&mut register).unwrap();
+
+        assert_eq!(register, Register::from("0, 0, 0, 20, 4, 5"));
+        assert_eq!(Instruction { opcode: Opcodes::seti, a: 1, b: 0, c: 6 }.apply(&mut register),
+                   Err(ApplyError::Register(RegisterError { register: 6, size: 6 })));
+        assert_eq!(Instruction { opcode: Opcodes::addr, a: -1, b: 0, c: 0 }.apply(&mut register),
+                   Err(ApplyError::Register(RegisterError { register: -1, size: 6 })));
+    }
+
+    #[test]
+    fn generators_agree_across_stray_blank_lines() {
+
+        let input = "Before: [3, 2, 1, 1]\n9 2 1 2\nAfter:  [3, 2, 2, 1]\n\n\nBefore: [0, 1, 2, 3]\n4 1 2 3\nAfter:  [0, 1, 2, 0]\n\n\n\n7 3 2 0\n";
+        let (samples, program) = input_generator_part2(input);
+
+        assert_eq!(samples.len(), 2);
+        assert_eq!(input_generator_part1(input), samples);
+        assert_eq!(program, vec![InstructionSet(7, 3, 2, 0)]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+
+        let mut generator = generator::PuzzleGenerator::random(24);
+        let mapping = generator.mapping().clone();
+
+        let json = serde_json::to_string(&mapping).unwrap();
+        assert_eq!(serde_json::from_str::<OpcodeMapping>(&json).unwrap(), mapping);
+        assert!(json.starts_with(&format!("[\"{}\"", mapping.get(0).unwrap())));
+
+        let test_case = generator.sample();
+        let json = serde_json::to_string(&test_case).unwrap();
+        assert_eq!(serde_json::from_str::<TestCase>(&json).unwrap(), test_case);
+
+        assert_eq!(serde_json::to_string(&Register::from("1, 2, 3")).unwrap(), "[1,2,3]");
+        assert_eq!(serde_json::to_string(&InstructionSet(9, 2, 1, 2)).unwrap(), "[9,2,1,2]");
+        assert_eq!(serde_json::from_str::<Opcodes>("{\"Custom\":3}").unwrap(), Opcodes::Custom(3));
+
+        let program = assembler::assemble("#ip 1\nseti 5 0 0").unwrap();
+        let steps : Vec<_> = trace::Trace::new(&program, Register::new(2)).collect();
+        let json = serde_json::to_string(&steps).unwrap();
+        assert_eq!(serde_json::from_str::<Vec<trace::TraceStep>>(&json).unwrap(), steps);
+
+        let mut lines = Vec::new();
+        trace::write_json_lines(&mut trace::Trace::new(&program, Register::new(2)), &mut lines).unwrap();
+        let read : Vec<trace::TraceStep> = String::from_utf8(lines).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(read, steps);
+        assert_eq!(serde_json::to_string(&steps[0]).unwrap(), steps[0].to_json());
+        assert_eq!(serde_json::from_str::<Program>(&serde_json::to_string(&program).unwrap()).unwrap(), program);
+    }
+}
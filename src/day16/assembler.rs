@@ -0,0 +1,214 @@
+use super::registry::OpcodeRegistry;
+use super::{Instruction, Program};
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AssembleErrorKind {
+    UnknownMnemonic(String),
+    UnknownDirective(String),
+    DuplicateDirective(String),
+    BadOperand(String),
+    MissingOperand,
+    UnexpectedToken(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssembleError {
+    pub line : usize,
+    pub column : usize,
+    pub kind : AssembleErrorKind,
+}
+
+impl fmt::Display for AssembleError {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: ", self.line, self.column)?;
+        match &self.kind {
+            AssembleErrorKind::UnknownMnemonic(name) => write!(f, "unknown mnemonic `{}`", name),
+            AssembleErrorKind::UnknownDirective(name) => write!(f, "unknown directive `{}`", name),
+            AssembleErrorKind::DuplicateDirective(name) => write!(f, "directive `{}` given more than once", name),
+            AssembleErrorKind::BadOperand(operand) => write!(f, "bad operand `{}`", operand),
+            AssembleErrorKind::MissingOperand => write!(f, "missing operand"),
+            AssembleErrorKind::UnexpectedToken(token) => write!(f, "unexpected `{}` after the last operand", token),
+        }
+    }
+
+}
+
+impl Error for AssembleError {}
+
+// Comments run from `;` or `//` to the end of the line.
+fn strip_comment(line : &str) -> &str {
+    let end = [line.find(';'), line.find("//")]
+        .iter()
+        .filter_map(|x| *x)
+        .min()
+        .unwrap_or(line.len());
+
+    &line[..end]
+}
+
+// Splits a line on whitespace, keeping the 1-based column each token starts at.
+fn tokens(line : &str) -> Vec<(usize, &str)> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+
+    line.char_indices()
+        .chain(std::iter::once((line.len(), ' ')))
+        .for_each(|(idx, ch)|{
+            match (start, ch.is_whitespace()) {
+                (None, false) => start = Some(idx),
+                (Some(s), true) => {
+                    tokens.push((line[..s].chars().count() + 1, &line[s..idx]));
+                    start = None;
+                },
+                _ => {}
+            }
+        });
+
+    tokens
+}
+
+enum Line {
+    Empty,
+    IpDirective(usize),
+    Instruction(Instruction),
+}
+
+fn parse_operands(line_number : usize, code : &str, tokens : &[(usize, &str)], operands : &mut [i64]) -> Result<(), AssembleError> {
+    let error = |column, kind| AssembleError { line: line_number, column, kind };
+
+    for (i, operand) in operands.iter_mut().enumerate() {
+        let (column, token) = match tokens.get(i) {
+            Some(token) => *token,
+            None => return Err(error(code.trim_end().chars().count() + 1, AssembleErrorKind::MissingOperand)),
+        };
+
+        *operand = token
+            .parse()
+            .map_err(|_| error(column, AssembleErrorKind::BadOperand(String::from(token))))?;
+    }
+
+    if let Some((column, token)) = tokens.get(operands.len()) {
+        return Err(error(*column, AssembleErrorKind::UnexpectedToken(String::from(*token))));
+    }
+
+    Ok(())
+}
+
+fn parse_line(line_number : usize, line : &str, registry : &OpcodeRegistry) -> Result<Line, AssembleError> {
+    let error = |column, kind| AssembleError { line: line_number, column, kind };
+
+    let code = strip_comment(line);
+    let tokens = tokens(code);
+    let (mnemonic_column, mnemonic) = match tokens.first() {
+        Some(token) => *token,
+        None => return Ok(Line::Empty),
+    };
+
+    if mnemonic.starts_with('#') {
+        if mnemonic != "#ip" {
+            return Err(error(mnemonic_column, AssembleErrorKind::UnknownDirective(String::from(mnemonic))));
+        }
+
+        let mut ip = [0i64];
+        parse_operands(line_number, code, &tokens[1..], &mut ip)?;
+
+        if ip[0] < 0 {
+            return Err(error(tokens[1].0, AssembleErrorKind::BadOperand(String::from(tokens[1].1))));
+        }
+
+        return Ok(Line::IpDirective(ip[0] as usize));
+    }
+
+    let opcode = registry.find(mnemonic)
+        .ok_or_else(|| error(mnemonic_column, AssembleErrorKind::UnknownMnemonic(String::from(mnemonic))))?;
+
+    let mut operands = [0i64; 3];
+    parse_operands(line_number, code, &tokens[1..], &mut operands)?;
+
+    Ok(Line::Instruction(Instruction { opcode, a: operands[0], b: operands[1], c: operands[2] }))
+}
+
+pub fn assemble(source : &str) -> Result<Program, AssembleError> {
+    assemble_in(source, OpcodeRegistry::builtin())
+}
+
+// Assembles with the mnemonics of every opcode in the registry.
+pub fn assemble_in(source : &str, registry : &OpcodeRegistry) -> Result<Program, AssembleError> {
+    let mut program = Program::default();
+
+    for (idx, line) in source.lines().enumerate() {
+        match parse_line(idx + 1, line, registry)? {
+            Line::Empty => {},
+            Line::IpDirective(register) => {
+                if program.ip_register.is_some() {
+                    let column = line.chars().take_while(|c| c.is_whitespace()).count() + 1;
+                    return Err(AssembleError { line: idx + 1, column, kind: AssembleErrorKind::DuplicateDirective(String::from("#ip")) });
+                }
+
+                program.ip_register = Some(register);
+            },
+            Line::Instruction(instruction) => program.instructions.push(instruction),
+        }
+    }
+
+    Ok(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{Opcodes, Register};
+
+    #[test]
+    fn assemble_with_comments_and_blank_lines() {
+
+        let program = assemble("; set up\nseti 5 0 1\n\n  addr 1 1 0 // double it\n").unwrap();
+
+        assert_eq!(program.ip_register, None);
+        assert_eq!(program.instructions, vec![
+            Instruction { opcode: Opcodes::seti, a: 5, b: 0, c: 1 },
+            Instruction { opcode: Opcodes::addr, a: 1, b: 1, c: 0 },
+        ]);
+
+        let mut register = Register::from("0, 0, 0, 0");
+        program.instructions.iter().for_each(|instruction| instruction.apply(&mut register).unwrap());
+
+        assert_eq!(register, Register::from("10, 5, 0, 0"));
+    }
+
+    #[test]
+    fn assemble_unknown_mnemonic() {
+
+        let error = assemble("seti 1 0 0\n  subr 1 2 3").unwrap_err();
+
+        assert_eq!(error, AssembleError { line: 2, column: 3, kind: AssembleErrorKind::UnknownMnemonic(String::from("subr")) });
+    }
+
+    #[test]
+    fn assemble_ip_directive() {
+
+        assert_eq!(assemble("#ip 3\nseti 0 0 3").unwrap().ip_register, Some(3));
+
+        assert_eq!(assemble("#ip 1\n#ip 2").unwrap_err(),
+                   AssembleError { line: 2, column: 1, kind: AssembleErrorKind::DuplicateDirective(String::from("#ip")) });
+
+        assert_eq!(assemble("#jmp 1").unwrap_err(),
+                   AssembleError { line: 1, column: 1, kind: AssembleErrorKind::UnknownDirective(String::from("#jmp")) });
+    }
+
+    #[test]
+    fn assemble_bad_operands() {
+
+        assert_eq!(assemble("addi 1 x 3").unwrap_err(),
+                   AssembleError { line: 1, column: 8, kind: AssembleErrorKind::BadOperand(String::from("x")) });
+
+        assert_eq!(assemble("addi 1 2 ; no target").unwrap_err(),
+                   AssembleError { line: 1, column: 9, kind: AssembleErrorKind::MissingOperand });
+
+        assert_eq!(assemble("addi 1 2 3 4").unwrap_err(),
+                   AssembleError { line: 1, column: 12, kind: AssembleErrorKind::UnexpectedToken(String::from("4")) });
+    }
+}
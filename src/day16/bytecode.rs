@@ -0,0 +1,342 @@
+use super::registry::OpcodeRegistry;
+use super::{InstructionSet, OpcodeMapping, Opcodes, Program};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+// Layout, version 1:
+//   magic `D16B`, version byte
+//   register count, ip register + 1 (0 when unbound), mapping length, each as a varint
+//   per mapped opcode: name length as a varint, then the name in UTF-8
+//   instruction count as a varint
+//   per instruction: opcode number as a byte, then a, b and c as zigzag varints
+// Varints are LEB128, so small operands take a single byte.
+const MAGIC : &[u8; 4] = b"D16B";
+pub const VERSION : u8 = 1;
+
+#[derive(Debug)]
+pub enum BytecodeError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u8),
+    BadVarint,
+    UnknownOpcodeName(String),
+    // A custom opcode the writing registry has no name for.
+    UnnamedOpcode(Opcodes),
+    BadOpcodeNumber { index : usize, number : i64 },
+    BadIpRegister { register : usize, register_count : usize },
+}
+
+impl From<io::Error> for BytecodeError {
+    fn from(error : io::Error) -> Self {
+        BytecodeError::Io(error)
+    }
+}
+
+impl fmt::Display for BytecodeError {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BytecodeError::Io(error) => write!(f, "bytecode i/o failed: {}", error),
+            BytecodeError::BadMagic => write!(f, "not day 16 bytecode"),
+            BytecodeError::UnsupportedVersion(version) => write!(f, "bytecode version {} is not supported, expected {}", version, VERSION),
+            BytecodeError::BadVarint => write!(f, "a number in the bytecode is malformed or too large"),
+            BytecodeError::UnknownOpcodeName(name) => write!(f, "the mapping names opcode `{}`, which is not registered here", name),
+            BytecodeError::UnnamedOpcode(opcode) => write!(f, "opcode {} is not registered here", opcode),
+            BytecodeError::BadOpcodeNumber { index, number } => write!(f, "instruction {} uses opcode number {}, which the mapping does not cover", index, number),
+            BytecodeError::BadIpRegister { register, register_count } => write!(f, "the instruction pointer is bound to register {}, but there are only {} registers", register, register_count),
+        }
+    }
+
+}
+
+impl Error for BytecodeError {
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BytecodeError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+
+}
+
+// A numeric program together with what it takes to run it: the register file size, the ip binding
+// and the meaning of each opcode number.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bytecode {
+    pub register_count : usize,
+    pub ip_register : Option<usize>,
+    pub mapping : OpcodeMapping,
+    pub instructions : Vec<InstructionSet>,
+}
+
+impl Bytecode {
+    // Numbers the opcodes a program uses in order of first use.
+    pub fn from_program(program : &Program, register_count : usize) -> Bytecode {
+        let mut opcodes : Vec<Opcodes> = Vec::new();
+
+        let instructions = program.instructions
+            .iter()
+            .map(|instruction|{
+                let number = match opcodes.iter().position(|&opcode| opcode == instruction.opcode) {
+                    Some(number) => number,
+                    None => {
+                        opcodes.push(instruction.opcode);
+                        opcodes.len() - 1
+                    },
+                };
+                InstructionSet(number as i64, instruction.a, instruction.b, instruction.c)
+            })
+            .collect();
+
+        Bytecode { register_count, ip_register: program.ip_register, mapping: OpcodeMapping::new(opcodes), instructions }
+    }
+
+    pub fn to_program(&self) -> Result<Program, BytecodeError> {
+        let instructions = self.instructions
+            .iter()
+            .enumerate()
+            .map(|(index, instruction)| self.mapping.decode(instruction).ok_or(BytecodeError::BadOpcodeNumber { index, number: instruction.0 }))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Program { ip_register: self.ip_register, instructions })
+    }
+
+    fn bad_ip_register(&self) -> Option<BytecodeError> {
+        self.ip_register
+            .filter(|&register| register >= self.register_count)
+            .map(|register| BytecodeError::BadIpRegister { register, register_count: self.register_count })
+    }
+
+    fn unmapped(&self) -> Option<BytecodeError> {
+        self.instructions
+            .iter()
+            .enumerate()
+            .find(|(_, instruction)| self.mapping.get(instruction.0).is_none() || instruction.0 > u8::MAX as i64)
+            .map(|(index, instruction)| BytecodeError::BadOpcodeNumber { index, number: instruction.0 })
+    }
+}
+
+fn write_varint<W : Write>(out : &mut W, mut value : u64) -> io::Result<()> {
+    let mut buffer = [0u8; 10];
+    let mut len = 0;
+
+    loop {
+        buffer[len] = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            break;
+        }
+        buffer[len] |= 0x80;
+        len += 1;
+    }
+
+    out.write_all(&buffer[..=len])
+}
+
+fn read_byte<R : Read>(input : &mut R) -> io::Result<u8> {
+    let mut byte = [0u8];
+    input.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_varint<R : Read>(input : &mut R) -> Result<u64, BytecodeError> {
+    let mut value = 0u64;
+
+    for shift in (0..64).step_by(7) {
+        let byte = read_byte(input)?;
+        let bits = u64::from(byte & 0x7f);
+
+        if bits << shift >> shift != bits {
+            return Err(BytecodeError::BadVarint);
+        }
+        value |= bits << shift;
+
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(BytecodeError::BadVarint)
+}
+
+fn read_usize<R : Read>(input : &mut R) -> Result<usize, BytecodeError> {
+    let value = read_varint(input)?;
+    if value > usize::MAX as u64 {
+        return Err(BytecodeError::BadVarint);
+    }
+    Ok(value as usize)
+}
+
+fn zigzag(value : i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value : u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+pub fn write<W : Write>(bytecode : &Bytecode, out : &mut W) -> Result<(), BytecodeError> {
+    write_in(bytecode, out, OpcodeRegistry::builtin())
+}
+
+// Custom opcodes are stored by the names `registry` gives them. Wrap `out` in a `BufWriter` when
+// it is unbuffered.
+pub fn write_in<W : Write>(bytecode : &Bytecode, out : &mut W, registry : &OpcodeRegistry) -> Result<(), BytecodeError> {
+    if let Some(error) = bytecode.bad_ip_register().or_else(|| bytecode.unmapped()) {
+        return Err(error);
+    }
+
+    out.write_all(MAGIC)?;
+    out.write_all(&[VERSION])?;
+    write_varint(out, bytecode.register_count as u64)?;
+    write_varint(out, bytecode.ip_register.map_or(0, |register| register as u64 + 1))?;
+
+    write_varint(out, bytecode.mapping.0.len() as u64)?;
+    for &opcode in &bytecode.mapping.0 {
+        let name = registry.name(opcode).ok_or(BytecodeError::UnnamedOpcode(opcode))?;
+        write_varint(out, name.len() as u64)?;
+        out.write_all(name.as_bytes())?;
+    }
+
+    write_varint(out, bytecode.instructions.len() as u64)?;
+    for InstructionSet(number, a, b, c) in &bytecode.instructions {
+        out.write_all(&[*number as u8])?;
+        write_varint(out, zigzag(*a))?;
+        write_varint(out, zigzag(*b))?;
+        write_varint(out, zigzag(*c))?;
+    }
+
+    Ok(())
+}
+
+pub fn read<R : Read>(input : &mut R) -> Result<Bytecode, BytecodeError> {
+    read_in(input, OpcodeRegistry::builtin())
+}
+
+// Opcode names are looked up in `registry`. Wrap `input` in a `BufReader` when it is unbuffered.
+pub fn read_in<R : Read>(input : &mut R, registry : &OpcodeRegistry) -> Result<Bytecode, BytecodeError> {
+    let mut magic = [0u8; 4];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(BytecodeError::BadMagic);
+    }
+
+    let version = read_byte(input)?;
+    if version != VERSION {
+        return Err(BytecodeError::UnsupportedVersion(version));
+    }
+
+    let register_count = read_usize(input)?;
+    let ip_register = read_usize(input)?.checked_sub(1);
+    if let Some(register) = ip_register.filter(|&register| register >= register_count) {
+        return Err(BytecodeError::BadIpRegister { register, register_count });
+    }
+
+    let mapping_len = read_usize(input)?;
+    let mut opcodes = Vec::new();
+    for _ in 0..mapping_len {
+        let len = read_varint(input)?;
+        let mut name = Vec::new();
+        if input.by_ref().take(len).read_to_end(&mut name)? as u64 != len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+
+        let name = String::from_utf8_lossy(&name).into_owned();
+        opcodes.push(registry.find(&name).ok_or(BytecodeError::UnknownOpcodeName(name))?);
+    }
+
+    // The count comes from the file, so it only sizes the first allocation up to a point.
+    let count = read_usize(input)?;
+    let mut instructions = Vec::with_capacity(count.min(1 << 16));
+    for index in 0..count {
+        let number = i64::from(read_byte(input)?);
+        if number as usize >= opcodes.len() {
+            return Err(BytecodeError::BadOpcodeNumber { index, number });
+        }
+
+        let a = unzigzag(read_varint(input)?);
+        let b = unzigzag(read_varint(input)?);
+        let c = unzigzag(read_varint(input)?);
+        instructions.push(InstructionSet(number, a, b, c));
+    }
+
+    Ok(Bytecode { register_count, ip_register, mapping: OpcodeMapping::new(opcodes), instructions })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::assembler::{assemble, assemble_in};
+    use super::super::generator::PuzzleGenerator;
+
+    #[test]
+    fn round_trip_generated_program() {
+
+        let mut generator = PuzzleGenerator::random(25);
+        let bytecode = Bytecode { register_count: 4, ip_register: None, mapping: generator.mapping().clone(), instructions: generator.program(1000) };
+
+        let mut bytes = Vec::new();
+        write(&bytecode, &mut bytes).unwrap();
+        assert_eq!(&bytes[..5], b"D16B\x01");
+        assert!(bytes.len() < 5 * 1000 + 200);
+        assert_eq!(read(&mut bytes.as_slice()).unwrap(), bytecode);
+
+        let program = assemble("#ip 2\nseti -300 0 0\naddi 0 9223372036854775807 1\nseti -300 0 0").unwrap();
+        let bytecode = Bytecode::from_program(&program, 3);
+        assert_eq!(bytecode.mapping, OpcodeMapping::new(vec![Opcodes::seti, Opcodes::addi]));
+
+        let mut bytes = Vec::new();
+        write(&bytecode, &mut bytes).unwrap();
+        assert_eq!(read(&mut bytes.as_slice()).unwrap().to_program().unwrap(), program);
+    }
+
+    #[test]
+    fn custom_opcodes_are_stored_by_name() {
+
+        let mut registry = OpcodeRegistry::new();
+        registry.register_binary("sub", |a, b, overflow| overflow.sub(a, b)).unwrap();
+        let program = assemble_in("subi 0 1 0", &registry).unwrap();
+        let bytecode = Bytecode::from_program(&program, 1);
+
+        let mut bytes = Vec::new();
+        assert!(matches!(write(&bytecode, &mut bytes), Err(BytecodeError::UnnamedOpcode(_))));
+
+        let mut bytes = Vec::new();
+        write_in(&bytecode, &mut bytes, &registry).unwrap();
+
+        let mut other = OpcodeRegistry::new();
+        other.register_binary("mul2", |a, _, overflow| overflow.mul(a, 2)).unwrap();
+        let (subr, subi) = other.register_binary("sub", |a, b, overflow| overflow.sub(a, b)).unwrap();
+        assert_eq!(read_in(&mut bytes.as_slice(), &other).unwrap().mapping, OpcodeMapping::new(vec![subi]));
+        assert_ne!(subr, subi);
+
+        match read(&mut bytes.as_slice()) {
+            Err(BytecodeError::UnknownOpcodeName(name)) => assert_eq!(name, "subi"),
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn malformed_bytecode_is_rejected() {
+
+        assert!(matches!(read(&mut &b"D16A\x01"[..]), Err(BytecodeError::BadMagic)));
+        assert!(matches!(read(&mut &b"D16B\x02"[..]), Err(BytecodeError::UnsupportedVersion(2))));
+        assert!(matches!(read(&mut &b"D16B\x01\x04"[..]), Err(BytecodeError::Io(_))));
+        assert!(matches!(read(&mut &b"D16B\x01\xff\xff\xff\xff\xff\xff\xff\xff\xff\x7f"[..]), Err(BytecodeError::BadVarint)));
+
+        // One mapped opcode, `seti`, but the instruction uses number 1.
+        assert!(matches!(read(&mut &b"D16B\x01\x04\x00\x01\x04seti\x01\x01\x00\x00\x00"[..]), Err(BytecodeError::BadOpcodeNumber { index: 0, number: 1 })));
+
+        let bytecode = Bytecode { register_count: 4, ip_register: None, mapping: OpcodeMapping::new(vec![Opcodes::seti]), instructions: vec![InstructionSet(3, 0, 0, 0)] };
+        assert!(matches!(write(&bytecode, &mut Vec::new()), Err(BytecodeError::BadOpcodeNumber { index: 0, number: 3 })));
+        assert!(matches!(bytecode.to_program(), Err(BytecodeError::BadOpcodeNumber { index: 0, number: 3 })));
+
+        // Four registers, but the instruction pointer is bound to register 4.
+        assert!(matches!(read(&mut &b"D16B\x01\x04\x05\x00\x00"[..]), Err(BytecodeError::BadIpRegister { register: 4, register_count: 4 })));
+
+        let bytecode = Bytecode { ip_register: Some(4), instructions: Vec::new(), ..bytecode };
+        assert!(matches!(write(&bytecode, &mut Vec::new()), Err(BytecodeError::BadIpRegister { register: 4, register_count: 4 })));
+    }
+}
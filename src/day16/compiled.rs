@@ -0,0 +1,230 @@
+use super::executor::VmError;
+use super::registry::OpcodeRegistry;
+use super::{Instruction, Opcodes, Overflow, Program, Register};
+
+// An instruction with its opcode resolved and every register operand checked against the register
+// file and stored as an index, so executing it needs neither a lookup, a cast nor an error path
+// for bad registers. Immediate operands stay `i64`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Addr(usize, usize, usize),
+    Addi(usize, i64, usize),
+    Mulr(usize, usize, usize),
+    Muli(usize, i64, usize),
+    Banr(usize, usize, usize),
+    Bani(usize, i64, usize),
+    Borr(usize, usize, usize),
+    Bori(usize, i64, usize),
+    Setr(usize, usize),
+    Seti(i64, usize),
+    Gtir(i64, usize, usize),
+    Gtri(usize, i64, usize),
+    Gtrr(usize, usize, usize),
+    Eqir(i64, usize, usize),
+    Eqri(usize, i64, usize),
+    Eqrr(usize, usize, usize),
+}
+
+impl Op {
+    // Only called on instructions `validate` has accepted, so every register operand is in range.
+    fn new(instruction : &Instruction) -> Option<Op> {
+        let Instruction { opcode, a, b, c } = *instruction;
+        let (ra, rb, c) = (a as usize, b as usize, c as usize);
+
+        Some(match opcode {
+            Opcodes::addr => Op::Addr(ra, rb, c),
+            Opcodes::addi => Op::Addi(ra, b, c),
+            Opcodes::mulr => Op::Mulr(ra, rb, c),
+            Opcodes::muli => Op::Muli(ra, b, c),
+            Opcodes::banr => Op::Banr(ra, rb, c),
+            Opcodes::bani => Op::Bani(ra, b, c),
+            Opcodes::borr => Op::Borr(ra, rb, c),
+            Opcodes::bori => Op::Bori(ra, b, c),
+            Opcodes::setr => Op::Setr(ra, c),
+            Opcodes::seti => Op::Seti(a, c),
+            Opcodes::gtir => Op::Gtir(a, rb, c),
+            Opcodes::gtri => Op::Gtri(ra, b, c),
+            Opcodes::gtrr => Op::Gtrr(ra, rb, c),
+            Opcodes::eqir => Op::Eqir(a, rb, c),
+            Opcodes::eqri => Op::Eqri(ra, b, c),
+            Opcodes::eqrr => Op::Eqrr(ra, rb, c),
+            Opcodes::Custom(_) => return None,
+        })
+    }
+
+    fn target(self) -> usize {
+        match self {
+            Op::Addr(_, _, c) | Op::Addi(_, _, c) | Op::Mulr(_, _, c) | Op::Muli(_, _, c)
+            | Op::Banr(_, _, c) | Op::Bani(_, _, c) | Op::Borr(_, _, c) | Op::Bori(_, _, c)
+            | Op::Setr(_, c) | Op::Seti(_, c)
+            | Op::Gtir(_, _, c) | Op::Gtri(_, _, c) | Op::Gtrr(_, _, c)
+            | Op::Eqir(_, _, c) | Op::Eqri(_, _, c) | Op::Eqrr(_, _, c) => c,
+        }
+    }
+
+    // Returns false, leaving the target unwritten, when checked arithmetic overflows. Inlined so each
+    // run loop gets its own dispatch.
+    #[inline(always)]
+    fn apply(self, r : &mut [i64], overflow : Overflow) -> bool {
+        let (value, c) = match self {
+            Op::Addr(a, b, c) => (overflow.add(r[a], r[b]), c),
+            Op::Addi(a, b, c) => (overflow.add(r[a], b), c),
+            Op::Mulr(a, b, c) => (overflow.mul(r[a], r[b]), c),
+            Op::Muli(a, b, c) => (overflow.mul(r[a], b), c),
+            Op::Banr(a, b, c) => (Some(r[a] & r[b]), c),
+            Op::Bani(a, b, c) => (Some(r[a] & b), c),
+            Op::Borr(a, b, c) => (Some(r[a] | r[b]), c),
+            Op::Bori(a, b, c) => (Some(r[a] | b), c),
+            Op::Setr(a, c) => (Some(r[a]), c),
+            Op::Seti(a, c) => (Some(a), c),
+            Op::Gtir(a, b, c) => (Some((a > r[b]) as i64), c),
+            Op::Gtri(a, b, c) => (Some((r[a] > b) as i64), c),
+            Op::Gtrr(a, b, c) => (Some((r[a] > r[b]) as i64), c),
+            Op::Eqir(a, b, c) => (Some((a == r[b]) as i64), c),
+            Op::Eqri(a, b, c) => (Some((r[a] == b) as i64), c),
+            Op::Eqrr(a, b, c) => (Some((r[a] == r[b]) as i64), c),
+        };
+
+        match value {
+            Some(value) => {
+                r[c] = value;
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+// A program decoded once for a fixed register count. Unlike `Machine`, which only faults when it
+// reaches a bad instruction, compiling rejects bad register operands anywhere in the program.
+// Only the built-in opcodes are supported; custom ones are reported as unknown.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompiledProgram {
+    register_count : usize,
+    ip_register : Option<usize>,
+    ops : Vec<Op>,
+    // Kept only to describe an overflow in the terms of the source program.
+    instructions : Vec<Instruction>,
+}
+
+impl CompiledProgram {
+    pub fn compile(program : &Program, register_count : usize) -> Result<CompiledProgram, VmError> {
+        OpcodeRegistry::builtin().validate(program, register_count)?;
+
+        let ops = program.instructions
+            .iter()
+            .enumerate()
+            .map(|(index, instruction)| Op::new(instruction).ok_or(VmError::UnknownOpcode { instruction: *instruction, index }))
+            .collect::<Result<Vec<Op>, VmError>>()?;
+
+        Ok(CompiledProgram { register_count, ip_register: program.ip_register, ops, instructions: program.instructions.clone() })
+    }
+
+    pub fn register_count(&self) -> usize {
+        self.register_count
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    // Runs from the first instruction until the pointer leaves the program and returns the number of
+    // instructions executed. The register file must have the size the program was compiled for.
+    // An overflow under checked arithmetic leaves the target register unwritten, as in `Machine`.
+    pub fn run(&self, register : &mut Register, overflow : Overflow) -> Result<u64, VmError> {
+        if register.len() != self.register_count {
+            return Err(VmError::RegisterCount { expected: self.register_count, found: register.len() });
+        }
+
+        match self.ip_register {
+            Some(ip_register) => self.run_bound(&mut register.0, ip_register, overflow),
+            None => self.run_unbound(&mut register.0, overflow),
+        }
+    }
+
+    // Without a pointer register nothing can jump, so the program runs straight through once.
+    fn run_unbound(&self, r : &mut [i64], overflow : Overflow) -> Result<u64, VmError> {
+        for (index, op) in self.ops.iter().enumerate() {
+            if !op.apply(r, overflow) {
+                return Err(self.overflow_at(index));
+            }
+        }
+
+        Ok(self.ops.len() as u64)
+    }
+
+    fn run_bound(&self, r : &mut [i64], ip_register : usize, overflow : Overflow) -> Result<u64, VmError> {
+        let mut ip = 0usize;
+        let mut steps = 0u64;
+
+        while let Some(op) = self.ops.get(ip) {
+            r[ip_register] = ip as i64;
+            if !op.apply(r, overflow) {
+                return Err(self.overflow_at(ip));
+            }
+
+            steps += 1;
+
+            // A negative pointer wraps to a huge index and halts like any other out-of-range one.
+            ip = if op.target() == ip_register { r[ip_register].wrapping_add(1) as usize } else { ip + 1 };
+        }
+
+        Ok(steps)
+    }
+
+    fn overflow_at(&self, index : usize) -> VmError {
+        VmError::Overflow { instruction: self.instructions[index], index }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::assembler::assemble;
+    use super::super::executor::Machine;
+
+    #[test]
+    fn compiled_run_matches_machine() {
+
+        let program = assemble("#ip 3
+                                seti 0 0 1
+                                addi 1 1 1
+                                eqri 1 5 2
+                                addr 2 3 3
+                                seti 0 0 3
+                                seti 9 0 0").unwrap();
+
+        let mut machine = Machine::new(&program, Register::new(4));
+        let expected = machine.run().unwrap().clone();
+
+        let compiled = CompiledProgram::compile(&program, 4).unwrap();
+        let mut register = Register::new(4);
+        assert_eq!(compiled.run(&mut register, Overflow::Checked), Ok(21));
+        assert_eq!(register, expected);
+
+        assert_eq!(compiled.run(&mut Register::new(6), Overflow::Checked), Err(VmError::RegisterCount { expected: 4, found: 6 }));
+
+        let program = assemble("seti 2 0 0\nmuli 0 4611686018427387904 0\naddi 0 1 1").unwrap();
+        let compiled = CompiledProgram::compile(&program, 2).unwrap();
+        assert_eq!(compiled.run(&mut Register::new(2), Overflow::Checked), Err(VmError::Overflow { instruction: program.instructions[1], index: 1 }));
+
+        let mut register = Register::new(2);
+        assert_eq!(compiled.run(&mut register, Overflow::Wrapping), Ok(3));
+        assert_eq!(register, Register(vec![i64::MIN, i64::MIN + 1]));
+    }
+
+    #[test]
+    fn compile_rejects_bad_registers() {
+
+        let program = assemble("seti 1 0 0\naddr 0 4 1\nseti 2 0 0").unwrap();
+        assert_eq!(CompiledProgram::compile(&program, 4), Err(VmError::BadRegister { register: 4, instruction: program.instructions[1], index: 1 }));
+        assert!(CompiledProgram::compile(&program, 5).is_ok());
+
+        let program = assemble("#ip 4\nseti 1 0 0").unwrap();
+        assert_eq!(CompiledProgram::compile(&program, 4), Err(VmError::BadIpRegister { register: 4, size: 4 }));
+    }
+}
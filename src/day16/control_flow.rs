@@ -0,0 +1,273 @@
+use super::{Instruction, Opcodes, Program, Register};
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+// Where control goes after a single instruction, as instruction indices (possibly outside the program).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    Next,
+    Jump(i64),
+    // A skip driven by a comparison result: the first target when it is 0, the second when it is 1.
+    Branch(i64, i64),
+    Indirect,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Edge {
+    Block(usize),
+    Halt,
+    Indirect,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub start : usize,
+    pub end : usize,
+    pub successors : Vec<Edge>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlFlowGraph {
+    pub blocks : Vec<BasicBlock>,
+}
+
+fn is_comparison(opcode : Opcodes) -> bool {
+    matches!(opcode, Opcodes::gtir | Opcodes::gtri | Opcodes::gtrr | Opcodes::eqir | Opcodes::eqri | Opcodes::eqrr)
+}
+
+// The registers other than the instruction pointer that an instruction reads.
+fn unknown_reads(instruction : &Instruction, ip : i64) -> Vec<i64> {
+    let (mode_a, mode_b) = instruction.opcode.modes();
+    let mut reads = Vec::new();
+    if mode_a.is_register() && instruction.a != ip {
+        reads.push(instruction.a);
+    }
+    if mode_b.is_register() && instruction.b != ip {
+        reads.push(instruction.b);
+    }
+    reads
+}
+
+fn flow(program : &Program, idx : usize, jump_targets : &BTreeSet<i64>) -> Flow {
+    let instruction = &program.instructions[idx];
+    let ip = match program.ip_register {
+        Some(ip) if instruction.c == ip as i64 => ip as i64,
+        _ => return Flow::Next,
+    };
+
+    let reads = unknown_reads(instruction, ip);
+
+    if reads.is_empty() {
+        // Only the pointer (whose value is known here) and immediates are read, so fold it.
+        let mut register = Register::new(ip as usize + 1);
+        register.set(ip as usize, idx as i64).unwrap();
+
+        return match instruction.apply(&mut register) {
+            Ok(()) => Flow::Jump(register.get(ip as usize).unwrap() + 1),
+            Err(_) => Flow::Indirect,
+        };
+    }
+
+    // `addr x ip ip` right after a comparison into x skips the next instruction when x is 1.
+    let previous = idx.checked_sub(1).map(|p| &program.instructions[p]);
+    let is_skip = instruction.opcode == Opcodes::addr
+        && reads.len() == 1
+        && !jump_targets.contains(&(idx as i64))
+        && previous.is_some_and(|p| is_comparison(p.opcode) && p.c == reads[0] && p.c != ip);
+
+    if is_skip {
+        Flow::Branch(idx as i64 + 1, idx as i64 + 2)
+    }
+    else {
+        Flow::Indirect
+    }
+}
+
+pub fn instruction_flows(program : &Program) -> Vec<Flow> {
+    let no_targets = BTreeSet::new();
+    let jump_targets : BTreeSet<i64> = (0..program.instructions.len())
+        .filter_map(|idx| match flow(program, idx, &no_targets) {
+            Flow::Jump(target) => Some(target),
+            _ => None,
+        })
+        .collect();
+
+    (0..program.instructions.len())
+        .map(|idx| flow(program, idx, &jump_targets))
+        .collect()
+}
+
+impl ControlFlowGraph {
+    pub fn build(program : &Program) -> ControlFlowGraph {
+        let len = program.instructions.len();
+        let flows = instruction_flows(program);
+        let in_range = |target : i64| target >= 0 && (target as usize) < len;
+
+        let mut leaders : BTreeSet<usize> = BTreeSet::new();
+        if len > 0 {
+            leaders.insert(0);
+        }
+
+        flows
+            .iter()
+            .enumerate()
+            .for_each(|(idx, flow)|{
+                let targets = match flow {
+                    Flow::Next => vec![],
+                    Flow::Jump(target) => vec![*target],
+                    Flow::Branch(not_taken, taken) => vec![*not_taken, *taken],
+                    Flow::Indirect => vec![],
+                };
+
+                if *flow != Flow::Next && idx + 1 < len {
+                    leaders.insert(idx + 1);
+                }
+
+                targets.into_iter().filter(|t| in_range(*t)).for_each(|t|{
+                    leaders.insert(t as usize);
+                });
+            });
+
+        let starts : Vec<usize> = leaders.into_iter().collect();
+        let block_of = |target : i64| {
+            if in_range(target) {
+                Edge::Block(starts.iter().position(|&s| s == target as usize).unwrap())
+            }
+            else {
+                Edge::Halt
+            }
+        };
+
+        let blocks = starts
+            .iter()
+            .enumerate()
+            .map(|(id, &start)|{
+                let end = starts.get(id + 1).copied().unwrap_or(len);
+                let successors = match flows[end - 1] {
+                    Flow::Next => vec![block_of(end as i64)],
+                    Flow::Jump(target) => vec![block_of(target)],
+                    Flow::Branch(not_taken, taken) => vec![block_of(not_taken), block_of(taken)],
+                    Flow::Indirect => vec![Edge::Indirect],
+                };
+
+                BasicBlock { start, end, successors }
+            })
+            .collect();
+
+        ControlFlowGraph { blocks }
+    }
+
+    pub fn block_containing(&self, idx : usize) -> Option<usize> {
+        self.blocks.iter().position(|block| block.start <= idx && idx < block.end)
+    }
+
+    pub fn to_dot(&self, program : &Program) -> String {
+        let mut dot = String::from("digraph program {\n    node [shape=box, fontname=monospace];\n");
+
+        self.blocks
+            .iter()
+            .enumerate()
+            .for_each(|(id, block)|{
+                let label : String = (block.start..block.end)
+                    .map(|idx| format!("{}: {}\\l", idx, program.instructions[idx]))
+                    .collect();
+                writeln!(dot, "    b{} [label=\"{}\"];", id, label).unwrap();
+            });
+
+        let mut uses_halt = false;
+        let mut uses_indirect = false;
+
+        self.blocks
+            .iter()
+            .enumerate()
+            .for_each(|(id, block)|{
+                let branch = block.successors.len() == 2;
+                block.successors
+                    .iter()
+                    .enumerate()
+                    .for_each(|(i, edge)|{
+                        let attributes = match (branch, i) {
+                            (true, 0) => " [label=\"0\"]",
+                            (true, _) => " [label=\"1\"]",
+                            _ => "",
+                        };
+                        match edge {
+                            Edge::Block(target) => writeln!(dot, "    b{} -> b{}{};", id, target, attributes),
+                            Edge::Halt => {
+                                uses_halt = true;
+                                writeln!(dot, "    b{} -> halt{};", id, attributes)
+                            },
+                            Edge::Indirect => {
+                                uses_indirect = true;
+                                writeln!(dot, "    b{} -> indirect [style=dashed];", id)
+                            },
+                        }
+                        .unwrap();
+                    });
+            });
+
+        if uses_halt {
+            dot.push_str("    halt [shape=doublecircle];\n");
+        }
+        if uses_indirect {
+            dot.push_str("    indirect [shape=diamond, label=\"?\"];\n");
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::assembler::assemble;
+
+    #[test]
+    fn blocks_and_constant_jumps() {
+
+        let program = assemble("#ip 0
+                                seti 5 0 1
+                                seti 6 0 2
+                                addi 0 1 0
+                                addr 1 2 3
+                                setr 1 0 0
+                                seti 8 0 4
+                                seti 9 0 5").unwrap();
+
+        let cfg = ControlFlowGraph::build(&program);
+
+        assert_eq!(cfg.blocks, vec![
+            BasicBlock { start: 0, end: 3, successors: vec![Edge::Block(2)] },
+            BasicBlock { start: 3, end: 4, successors: vec![Edge::Block(2)] },
+            BasicBlock { start: 4, end: 5, successors: vec![Edge::Indirect] },
+            BasicBlock { start: 5, end: 7, successors: vec![Edge::Halt] },
+        ]);
+
+        let dot = cfg.to_dot(&program);
+        assert!(dot.contains("b0 [label=\"0: seti 5 0 1\\l1: seti 6 0 2\\l2: addi 0 1 0\\l\"];"));
+        assert!(dot.contains("b2 -> indirect [style=dashed];"));
+        assert!(dot.contains("b3 -> halt;"));
+    }
+
+    #[test]
+    fn comparison_skips_become_branches() {
+
+        let program = assemble("#ip 3
+                                addi 1 1 1
+                                eqri 1 5 2
+                                addr 2 3 3
+                                seti 0 0 3
+                                seti 9 0 0").unwrap();
+
+        let cfg = ControlFlowGraph::build(&program);
+
+        assert_eq!(cfg.blocks, vec![
+            BasicBlock { start: 0, end: 1, successors: vec![Edge::Block(1)] },
+            BasicBlock { start: 1, end: 3, successors: vec![Edge::Block(2), Edge::Block(3)] },
+            BasicBlock { start: 3, end: 4, successors: vec![Edge::Block(1)] },
+            BasicBlock { start: 4, end: 5, successors: vec![Edge::Halt] },
+        ]);
+        assert_eq!(cfg.block_containing(2), Some(1));
+    }
+}
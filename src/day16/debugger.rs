@@ -0,0 +1,291 @@
+use super::executor::{Machine, VmError};
+use super::registry::OpcodeRegistry;
+use super::{Overflow, Program, Register, RegisterError};
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    Stepped,
+    Halted,
+    Breakpoint(usize),
+    Watchpoint { register : usize, old : i64, new : i64 },
+    Fault(VmError),
+}
+
+pub struct Debugger<'a> {
+    machine : Machine<'a>,
+    breakpoints : BTreeSet<usize>,
+    watchpoints : BTreeSet<usize>,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(program : &'a Program, register : Register) -> Debugger<'a> {
+        Debugger { machine: Machine::new(program, register), breakpoints: BTreeSet::new(), watchpoints: BTreeSet::new() }
+    }
+
+    // Arithmetic is checked unless chosen otherwise here, as for `Machine`.
+    pub fn with_overflow(mut self, overflow : Overflow) -> Debugger<'a> {
+        self.machine = self.machine.with_overflow(overflow);
+        self
+    }
+
+    // Needed to run, and name, custom opcodes.
+    pub fn with_registry(mut self, registry : &'a OpcodeRegistry) -> Debugger<'a> {
+        self.machine = self.machine.with_registry(registry);
+        self
+    }
+
+    pub fn machine(&self) -> &Machine<'a> {
+        &self.machine
+    }
+
+    pub fn add_breakpoint(&mut self, index : usize) {
+        self.breakpoints.insert(index);
+    }
+
+    pub fn remove_breakpoint(&mut self, index : usize) -> bool {
+        self.breakpoints.remove(&index)
+    }
+
+    pub fn watch(&mut self, register : usize) {
+        self.watchpoints.insert(register);
+    }
+
+    pub fn unwatch(&mut self, register : usize) -> bool {
+        self.watchpoints.remove(&register)
+    }
+
+    pub fn poke(&mut self, register : usize, value : i64) -> Result<(), RegisterError> {
+        self.machine.register_mut().set(register, value)
+    }
+
+    pub fn step(&mut self) -> StopReason {
+        let before = self.machine.register().clone();
+
+        match self.machine.step() {
+            Ok(false) => return StopReason::Halted,
+            Ok(true) => {},
+            Err(error) => return StopReason::Fault(error),
+        }
+
+        let changed = self.watchpoints
+            .iter()
+            .map(|&register| (register, before.get(register), self.machine.register().get(register)))
+            .find(|(_, old, new)| old != new);
+
+        if let Some((register, Some(old), Some(new))) = changed {
+            return StopReason::Watchpoint { register, old, new };
+        }
+
+        if self.machine.is_halted() {
+            StopReason::Halted
+        }
+        else {
+            StopReason::Stepped
+        }
+    }
+
+    // Runs until a breakpoint, watchpoint, halt or fault. Always executes at least one instruction,
+    // so continuing from a breakpoint moves past it.
+    pub fn cont(&mut self) -> StopReason {
+        loop {
+            match self.step() {
+                StopReason::Stepped => {},
+                reason => return reason,
+            }
+
+            let ip = self.machine.ip() as usize;
+            if self.breakpoints.contains(&ip) {
+                return StopReason::Breakpoint(ip);
+            }
+        }
+    }
+
+    fn location(&self) -> String {
+        match self.machine.current_instruction() {
+            Some(instruction) => format!("{:>4}: {}", self.machine.ip(), self.machine.registry().format_instruction(instruction)),
+            None => format!("{:>4}: <halted>", self.machine.ip()),
+        }
+    }
+
+    fn describe(&self, reason : &StopReason) -> String {
+        match reason {
+            StopReason::Stepped => self.location(),
+            StopReason::Halted => format!("program halted with {}", self.machine.register()),
+            StopReason::Breakpoint(index) => format!("breakpoint at {}\n{}", index, self.location()),
+            StopReason::Watchpoint { register, old, new } => format!("r{} changed from {} to {}\n{}", register, old, new, self.location()),
+            StopReason::Fault(error) => format!("fault: {}", error),
+        }
+    }
+
+    fn listing(&self) -> String {
+        let ip = self.machine.ip();
+        let instructions = &self.machine.program().instructions;
+
+        instructions
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| (*idx as i64 - ip).abs() <= 3)
+            .map(|(idx, instruction)|{
+                let marker = if idx as i64 == ip { "=>" } else { "  " };
+                let breakpoint = if self.breakpoints.contains(&idx) { "*" } else { " " };
+                format!("{}{}{:>4}: {}", marker, breakpoint, idx, self.machine.registry().format_instruction(instruction))
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    // Runs one command line and returns what should be printed, or None to quit.
+    pub fn execute(&mut self, command : &str) -> Option<String> {
+        let words : Vec<&str> = command.split_whitespace().collect();
+        let register_arg = |word : Option<&&str>| word.and_then(|w| w.trim_start_matches('r').parse::<usize>().ok());
+        let index_arg = |word : Option<&&str>| word.and_then(|w| w.parse::<usize>().ok());
+
+        let output = match words.first().copied() {
+            None => String::new(),
+            Some("s") | Some("step") => {
+                let count = index_arg(words.get(1)).unwrap_or(1);
+                let mut reason = StopReason::Stepped;
+                for _ in 0..count {
+                    reason = self.step();
+                    if reason != StopReason::Stepped {
+                        break;
+                    }
+                }
+                self.describe(&reason)
+            },
+            Some("c") | Some("continue") => {
+                let reason = self.cont();
+                self.describe(&reason)
+            },
+            Some("b") | Some("break") => match index_arg(words.get(1)) {
+                Some(index) => {
+                    self.add_breakpoint(index);
+                    format!("breakpoint set at {}", index)
+                },
+                None => String::from("usage: break <instruction index>"),
+            },
+            Some("d") | Some("delete") => match index_arg(words.get(1)) {
+                Some(index) if self.remove_breakpoint(index) => format!("breakpoint at {} removed", index),
+                Some(index) => format!("no breakpoint at {}", index),
+                None => String::from("usage: delete <instruction index>"),
+            },
+            Some("w") | Some("watch") => match register_arg(words.get(1)) {
+                Some(register) if register < self.machine.register().len() => {
+                    self.watch(register);
+                    format!("watching r{}", register)
+                },
+                _ => String::from("usage: watch r<register>"),
+            },
+            Some("unwatch") => match register_arg(words.get(1)) {
+                Some(register) if self.unwatch(register) => format!("no longer watching r{}", register),
+                _ => String::from("usage: unwatch r<register>"),
+            },
+            Some("set") => match (register_arg(words.get(1)), words.get(2).and_then(|w| w.parse::<i64>().ok())) {
+                (Some(register), Some(value)) => match self.poke(register, value) {
+                    Ok(()) => format!("{}", self.machine.register()),
+                    Err(error) => format!("{}", error),
+                },
+                _ => String::from("usage: set r<register> <value>"),
+            },
+            Some("r") | Some("regs") => format!("ip = {}, registers = {}", self.machine.ip(), self.machine.register()),
+            Some("l") | Some("list") => self.listing(),
+            Some("q") | Some("quit") => return None,
+            Some("h") | Some("help") => String::from(HELP),
+            Some(other) => format!("unknown command `{}`, try `help`", other),
+        };
+
+        Some(output)
+    }
+}
+
+const HELP : &str = "\
+step [n]        execute n instructions (default 1)
+continue        run until a breakpoint, watchpoint, halt or fault
+break <index>   stop before the instruction at <index>
+delete <index>  remove a breakpoint
+watch r<n>      stop when register n changes
+unwatch r<n>    stop watching register n
+set r<n> <v>    write v into register n
+regs            show the instruction pointer and registers
+list            show the instructions around the pointer
+quit            leave the debugger";
+
+pub fn repl<R : BufRead, W : Write>(debugger : &mut Debugger, input : R, output : &mut W) -> io::Result<()> {
+    write!(output, "{}\n(dbg) ", debugger.location())?;
+    output.flush()?;
+
+    for line in input.lines() {
+        match debugger.execute(&line?) {
+            Some(response) => {
+                if !response.is_empty() {
+                    writeln!(output, "{}", response)?;
+                }
+                write!(output, "(dbg) ")?;
+                output.flush()?;
+            },
+            None => break,
+        }
+    }
+
+    writeln!(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::assembler::assemble;
+
+    fn countdown() -> Program {
+        assemble("#ip 3
+                  seti 3 0 1
+                  addi 1 -1 1
+                  addi 0 10 0
+                  gtri 1 0 2
+                  addr 3 2 3
+                  seti 6 0 3
+                  seti 0 0 3").unwrap()
+    }
+
+    #[test]
+    fn breakpoints_and_watchpoints() {
+
+        let program = countdown();
+        let mut debugger = Debugger::new(&program, Register::new(4));
+
+        debugger.add_breakpoint(2);
+        assert_eq!(debugger.cont(), StopReason::Breakpoint(2));
+        assert_eq!(debugger.cont(), StopReason::Breakpoint(2));
+        assert_eq!(debugger.machine().register().get(1), Some(1));
+
+        debugger.remove_breakpoint(2);
+        debugger.watch(0);
+        assert_eq!(debugger.cont(), StopReason::Watchpoint { register: 0, old: 10, new: 20 });
+
+        debugger.unwatch(0);
+        assert_eq!(debugger.cont(), StopReason::Halted);
+        assert_eq!(debugger.machine().register().get(0), Some(30));
+
+        let program = assemble("seti 2 0 0\nmuli 0 4611686018427387904 0").unwrap();
+        let mut debugger = Debugger::new(&program, Register::new(1)).with_overflow(Overflow::Saturating);
+        assert_eq!(debugger.cont(), StopReason::Halted);
+        assert_eq!(debugger.machine().register().get(0), Some(i64::MAX));
+    }
+
+    #[test]
+    fn repl_commands() {
+
+        let program = countdown();
+        let mut debugger = Debugger::new(&program, Register::new(4));
+        let mut output = Vec::new();
+
+        repl(&mut debugger, "step 2\nset r1 0\nbreak 9\nc\nquit\nstep\n".as_bytes(), &mut output).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("   2: addi 0 10 0"));
+        assert!(output.contains("[0, 0, 0, 1]"));
+        assert!(output.contains("breakpoint set at 9"));
+        assert!(output.contains("program halted with [10, 0, 0, 6]"));
+    }
+}
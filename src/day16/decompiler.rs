@@ -0,0 +1,774 @@
+use super::control_flow::{instruction_flows, ControlFlowGraph, Flow};
+use super::registry::OpcodeRegistry;
+use super::{Instruction, Opcodes, Program};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Mul,
+    And,
+    Or,
+    Gt,
+    Le,
+    Eq,
+    Ne,
+}
+
+impl BinOp {
+    fn symbol(self) -> &'static str {
+        match self {
+            BinOp::Add => "+",
+            BinOp::Mul => "*",
+            BinOp::And => "&",
+            BinOp::Or => "|",
+            BinOp::Gt => ">",
+            BinOp::Le => "<=",
+            BinOp::Eq => "==",
+            BinOp::Ne => "!=",
+        }
+    }
+
+    fn precedence(self) -> u8 {
+        match self {
+            BinOp::Mul => 5,
+            BinOp::Add => 4,
+            BinOp::Gt | BinOp::Le => 3,
+            BinOp::Eq | BinOp::Ne => 2,
+            BinOp::And => 1,
+            BinOp::Or => 0,
+        }
+    }
+
+    fn is_associative(self) -> bool {
+        matches!(self, BinOp::Add | BinOp::Mul | BinOp::And | BinOp::Or)
+    }
+
+    fn negated(self) -> Option<BinOp> {
+        match self {
+            BinOp::Gt => Some(BinOp::Le),
+            BinOp::Le => Some(BinOp::Gt),
+            BinOp::Eq => Some(BinOp::Ne),
+            BinOp::Ne => Some(BinOp::Eq),
+            _ => None,
+        }
+    }
+
+    fn eval(self, a : i64, b : i64) -> i64 {
+        match self {
+            BinOp::Add => a.wrapping_add(b),
+            BinOp::Mul => a.wrapping_mul(b),
+            BinOp::And => a & b,
+            BinOp::Or => a | b,
+            BinOp::Gt => (a > b) as i64,
+            BinOp::Le => (a <= b) as i64,
+            BinOp::Eq => (a == b) as i64,
+            BinOp::Ne => (a != b) as i64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Const(i64),
+    Reg(i64),
+    Bin(BinOp, Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    // A custom opcode, by its registry name, applied to its operands.
+    Call(String, Vec<Expr>),
+}
+
+impl Expr {
+    // Builds a binary expression, folding constants as it goes.
+    fn bin(op : BinOp, a : Expr, b : Expr) -> Expr {
+        match (op, a, b) {
+            (op, Expr::Const(x), Expr::Const(y)) => Expr::Const(op.eval(x, y)),
+            (BinOp::Add, Expr::Const(0), e) | (BinOp::Add, e, Expr::Const(0)) => e,
+            (BinOp::Mul, Expr::Const(1), e) | (BinOp::Mul, e, Expr::Const(1)) => e,
+            (BinOp::Add, Expr::Bin(BinOp::Add, inner, x), Expr::Const(y)) if matches!(*x, Expr::Const(_)) => {
+                let x = if let Expr::Const(x) = *x { x } else { unreachable!() };
+                Expr::bin(BinOp::Add, *inner, Expr::Const(x.wrapping_add(y)))
+            },
+            (BinOp::Add, Expr::Const(x), e) => Expr::bin(BinOp::Add, e, Expr::Const(x)),
+            (op, a, b) => Expr::Bin(op, Box::new(a), Box::new(b)),
+        }
+    }
+
+    // Every register the expression reads, including those of values folded into it.
+    fn registers(&self, out : &mut BTreeSet<i64>) {
+        match self {
+            Expr::Const(_) => {},
+            Expr::Reg(r) => { out.insert(*r); },
+            Expr::Bin(_, a, b) => {
+                a.registers(out);
+                b.registers(out);
+            },
+            Expr::Not(e) => e.registers(out),
+            Expr::Call(_, args) => args.iter().for_each(|arg| arg.registers(out)),
+        }
+    }
+
+    fn negate(self) -> Expr {
+        match self {
+            Expr::Bin(op, a, b) if op.negated().is_some() => Expr::Bin(op.negated().unwrap(), a, b),
+            Expr::Not(e) => *e,
+            Expr::Const(x) => Expr::Const((x == 0) as i64),
+            e => Expr::Not(Box::new(e)),
+        }
+    }
+
+    fn fmt_prec(&self, f : &mut fmt::Formatter<'_>, parent : Option<(BinOp, bool)>) -> fmt::Result {
+        match self {
+            Expr::Const(x) => write!(f, "{}", x),
+            Expr::Reg(r) => write!(f, "r{}", r),
+            Expr::Call(name, args) => {
+                let args : Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                write!(f, "{}({})", name, args.join(", "))
+            },
+            Expr::Not(e) => {
+                write!(f, "!(")?;
+                e.fmt_prec(f, None)?;
+                write!(f, ")")
+            },
+            Expr::Bin(op, a, b) => {
+                let parens = match parent {
+                    None => false,
+                    Some((parent_op, right)) => op.precedence() < parent_op.precedence()
+                        || (op.precedence() == parent_op.precedence() && right && !(parent_op == *op && op.is_associative())),
+                };
+
+                if parens {
+                    write!(f, "(")?;
+                }
+                a.fmt_prec(f, Some((*op, false)))?;
+                write!(f, " {} ", op.symbol())?;
+                b.fmt_prec(f, Some((*op, true)))?;
+                if parens {
+                    write!(f, ")")?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_prec(f, None)
+    }
+
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Label(usize),
+    Halt,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node {
+    Label(usize),
+    Assign(i64, Expr),
+    Goto(Target),
+    CondGoto(Expr, Target),
+    IndirectJump(Expr),
+    If(Expr, Vec<Node>, Vec<Node>),
+    While(Expr, Vec<Node>),
+    DoWhile(Vec<Node>, Expr),
+    Loop(Vec<Node>),
+    Break,
+}
+
+// One entry per register operand, so a register read twice is listed twice.
+fn register_reads(instruction : &Instruction, ip : Option<i64>, registry : &OpcodeRegistry) -> Vec<i64> {
+    let (mode_a, mode_b) = registry.modes(instruction.opcode);
+    let mut reads = Vec::new();
+    if mode_a.is_register() && Some(instruction.a) != ip {
+        reads.push(instruction.a);
+    }
+    if mode_b.is_register() && Some(instruction.b) != ip {
+        reads.push(instruction.b);
+    }
+    reads
+}
+
+// The value an instruction computes, with the pointer register read as the constant index.
+fn value(instruction : &Instruction, idx : usize, ip : Option<i64>, pending : &mut HashMap<i64, Expr>, registry : &OpcodeRegistry) -> Expr {
+    let mut reg = |r : i64| {
+        if Some(r) == ip {
+            Expr::Const(idx as i64)
+        }
+        else {
+            pending.remove(&r).unwrap_or(Expr::Reg(r))
+        }
+    };
+
+    let (a, b) = (instruction.a, instruction.b);
+    match instruction.opcode {
+        Opcodes::addr => { let x = reg(a); Expr::bin(BinOp::Add, x, reg(b)) },
+        Opcodes::addi => Expr::bin(BinOp::Add, reg(a), Expr::Const(b)),
+        Opcodes::mulr => { let x = reg(a); Expr::bin(BinOp::Mul, x, reg(b)) },
+        Opcodes::muli => Expr::bin(BinOp::Mul, reg(a), Expr::Const(b)),
+        Opcodes::banr => { let x = reg(a); Expr::bin(BinOp::And, x, reg(b)) },
+        Opcodes::bani => Expr::bin(BinOp::And, reg(a), Expr::Const(b)),
+        Opcodes::borr => { let x = reg(a); Expr::bin(BinOp::Or, x, reg(b)) },
+        Opcodes::bori => Expr::bin(BinOp::Or, reg(a), Expr::Const(b)),
+        Opcodes::setr => reg(a),
+        Opcodes::seti => Expr::Const(a),
+        Opcodes::gtir => Expr::bin(BinOp::Gt, Expr::Const(a), reg(b)),
+        Opcodes::gtri => Expr::bin(BinOp::Gt, reg(a), Expr::Const(b)),
+        Opcodes::gtrr => { let x = reg(a); Expr::bin(BinOp::Gt, x, reg(b)) },
+        Opcodes::eqir => Expr::bin(BinOp::Eq, Expr::Const(a), reg(b)),
+        Opcodes::eqri => Expr::bin(BinOp::Eq, reg(a), Expr::Const(b)),
+        Opcodes::eqrr => { let x = reg(a); Expr::bin(BinOp::Eq, x, reg(b)) },
+        Opcodes::Custom(_) => {
+            let (mode_a, mode_b) = registry.modes(instruction.opcode);
+            let x = if mode_a.is_register() { reg(a) } else { Expr::Const(a) };
+            let y = if mode_b.is_register() { reg(b) } else { Expr::Const(b) };
+            Expr::Call(registry.display_name(instruction.opcode), vec![x, y])
+        },
+    }
+}
+
+fn successors(flow : Flow, idx : usize) -> Vec<Option<i64>> {
+    match flow {
+        Flow::Next => vec![Some(idx as i64 + 1)],
+        Flow::Jump(target) => vec![Some(target)],
+        Flow::Branch(not_taken, taken) => vec![Some(not_taken), Some(taken)],
+        Flow::Indirect => vec![None],
+    }
+}
+
+// Registers live on exit from each instruction. An indirect jump keeps everything live; on halt only
+// r0 is, since that is where the puzzles leave their answer.
+fn live_out(program : &Program, flows : &[Flow], registry : &OpcodeRegistry) -> Vec<BTreeSet<i64>> {
+    let ip = program.ip_register.map(|r| r as i64);
+    let len = program.instructions.len();
+    let all : BTreeSet<i64> = program.instructions
+        .iter()
+        .flat_map(|i|{
+            let (mode_a, mode_b) = registry.modes(i.opcode);
+            vec![i.a, i.b, i.c].into_iter().zip(vec![mode_a.is_register(), mode_b.is_register(), true])
+        })
+        .filter(|(r, is_register)| *is_register && Some(*r) != ip)
+        .map(|(r, _)| r)
+        .chain(0..1)
+        .collect();
+
+    let mut live_in = vec![BTreeSet::new(); len];
+    let mut live_out = vec![BTreeSet::new(); len];
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+
+        for idx in (0..len).rev() {
+            let out : BTreeSet<i64> = successors(flows[idx], idx)
+                .into_iter()
+                .flat_map(|target| match target {
+                    Some(t) if t >= 0 && (t as usize) < len => live_in[t as usize].clone(),
+                    Some(_) => (0..1).collect(),
+                    None => all.clone(),
+                })
+                .collect();
+
+            let instruction = &program.instructions[idx];
+            let mut inn = out.clone();
+            if Some(instruction.c) != ip {
+                inn.remove(&instruction.c);
+            }
+            inn.extend(register_reads(instruction, ip, registry));
+
+            if inn != live_in[idx] || out != live_out[idx] {
+                live_in[idx] = inn;
+                live_out[idx] = out;
+                changed = true;
+            }
+        }
+    }
+
+    live_out
+}
+
+fn target(program : &Program, t : i64) -> Target {
+    if t >= 0 && (t as usize) < program.instructions.len() {
+        Target::Label(t as usize)
+    }
+    else {
+        Target::Halt
+    }
+}
+
+// Turns the program into a flat list of labels, assignments and jumps. Within a basic block a value
+// read exactly once and dead afterwards is folded into the expression that reads it.
+pub fn lift(program : &Program) -> Vec<Node> {
+    lift_in(program, OpcodeRegistry::builtin())
+}
+
+// As `lift`, taking names and operand modes of custom opcodes from the registry.
+pub fn lift_in(program : &Program, registry : &OpcodeRegistry) -> Vec<Node> {
+    let ip = program.ip_register.map(|r| r as i64);
+    let flows = instruction_flows(program);
+    let live = live_out(program, &flows, registry);
+    let cfg = ControlFlowGraph::build(program);
+    let mut nodes = Vec::new();
+
+    for block in &cfg.blocks {
+        nodes.push(Node::Label(block.start));
+        let mut pending : HashMap<i64, Expr> = HashMap::new();
+
+        for (idx, instruction) in program.instructions.iter().enumerate().take(block.end).skip(block.start) {
+            match flows[idx] {
+                Flow::Next | Flow::Indirect => {},
+                Flow::Jump(t) => {
+                    nodes.push(Node::Goto(target(program, t)));
+                    continue;
+                },
+                Flow::Branch(_, taken) => {
+                    let condition = register_reads(instruction, ip, registry)[0];
+                    let condition = pending.remove(&condition).unwrap_or(Expr::Reg(condition));
+                    nodes.push(Node::CondGoto(condition, target(program, taken)));
+                    continue;
+                },
+            }
+
+            let expr = value(instruction, idx, ip, &mut pending, registry);
+            if flows[idx] == Flow::Indirect {
+                nodes.push(Node::IndirectJump(Expr::bin(BinOp::Add, expr, Expr::Const(1))));
+                continue;
+            }
+
+            let def = instruction.c;
+            let mut readers = Vec::new();
+            let mut redefined = false;
+            for later in idx + 1..block.end {
+                let other = &program.instructions[later];
+                readers.extend(register_reads(other, ip, registry).into_iter().filter(|&r| r == def).map(|_| later));
+                if other.c == def {
+                    redefined = true;
+                    break;
+                }
+            }
+
+            let dead_after = redefined || !live[block.end - 1].contains(&def);
+            let mut sources = BTreeSet::new();
+            expr.registers(&mut sources);
+            let clobbered = |until : usize| (idx + 1..until).any(|m| {
+                let c = program.instructions[m].c;
+                sources.contains(&c) || c == def
+            });
+
+            if readers.is_empty() && dead_after {
+                continue;
+            }
+
+            if readers.len() == 1 && dead_after && !clobbered(readers[0]) {
+                pending.insert(def, expr);
+                continue;
+            }
+
+            nodes.push(Node::Assign(def, expr));
+        }
+
+        if block.end == program.instructions.len() && flows[block.end - 1] == Flow::Next {
+            nodes.push(Node::Goto(Target::Halt));
+        }
+    }
+
+    nodes
+}
+
+fn count_refs(nodes : &[Node], refs : &mut HashMap<usize, usize>) {
+    nodes.iter().for_each(|node| match node {
+        Node::Goto(Target::Label(l)) | Node::CondGoto(_, Target::Label(l)) => *refs.entry(*l).or_insert(0) += 1,
+        Node::If(_, a, b) => {
+            count_refs(a, refs);
+            count_refs(b, refs);
+        },
+        Node::While(_, body) | Node::DoWhile(body, _) | Node::Loop(body) => count_refs(body, refs),
+        _ => {},
+    });
+}
+
+fn jump_target(node : &Node) -> Option<usize> {
+    match node {
+        Node::Goto(Target::Label(l)) | Node::CondGoto(_, Target::Label(l)) => Some(*l),
+        _ => None,
+    }
+}
+
+// The labels that sit directly at `pos` and after it, before the next statement.
+fn labels_at(nodes : &[Node], pos : usize) -> Vec<usize> {
+    nodes[pos.min(nodes.len())..]
+        .iter()
+        .map_while(|node| match node {
+            Node::Label(l) => Some(*l),
+            _ => None,
+        })
+        .collect()
+}
+
+fn label_position(nodes : &[Node], label : usize) -> Option<usize> {
+    nodes.iter().position(|node| *node == Node::Label(label))
+}
+
+// A region can be nested when nothing outside it jumps to a label inside it.
+fn self_contained(region : &[Node], refs : &HashMap<usize, usize>) -> bool {
+    let mut inside = HashMap::new();
+    count_refs(region, &mut inside);
+
+    region.iter().all(|node| match node {
+        Node::Label(l) => inside.get(l).copied().unwrap_or(0) == refs.get(l).copied().unwrap_or(0),
+        _ => true,
+    })
+}
+
+fn release(refs : &mut HashMap<usize, usize>, label : usize) {
+    if let Some(count) = refs.get_mut(&label) {
+        *count -= 1;
+    }
+}
+
+fn simplify(nodes : &mut Vec<Node>, refs : &mut HashMap<usize, usize>) {
+    let mut i = 0;
+    while i < nodes.len() {
+        // A jump to the very next statement does nothing.
+        if let Some(l) = jump_target(&nodes[i]) {
+            if labels_at(nodes, i + 1).contains(&l) {
+                release(refs, l);
+                nodes.remove(i);
+                continue;
+            }
+        }
+
+        // `if (c) goto A; goto B; A:` becomes `if (!c) goto B; A:`.
+        if let (Node::CondGoto(_, Target::Label(a)), Some(Node::Goto(b))) = (&nodes[i], nodes.get(i + 1)) {
+            let (a, b) = (*a, *b);
+            if labels_at(nodes, i + 2).contains(&a) {
+                if let Node::CondGoto(c, _) = nodes.remove(i) {
+                    release(refs, a);
+                    nodes[i] = Node::CondGoto(c.negate(), b);
+                    continue;
+                }
+            }
+        }
+
+        i += 1;
+    }
+}
+
+// Rewrites jumps to the label just past a loop as `break`, outside of nested loops. With `halts`
+// set, halting is treated as leaving the loop too.
+fn breaks(nodes : Vec<Node>, exit : &[usize], halts : bool, refs : &mut HashMap<usize, usize>) -> Vec<Node> {
+    let is_exit = |target : Target| match target {
+        Target::Label(l) => exit.contains(&l),
+        Target::Halt => halts,
+    };
+
+    nodes
+        .into_iter()
+        .map(|node| match node {
+            Node::Goto(t) if is_exit(t) => {
+                if let Target::Label(l) = t {
+                    release(refs, l);
+                }
+                Node::Break
+            },
+            Node::CondGoto(c, t) if is_exit(t) => {
+                if let Target::Label(l) = t {
+                    release(refs, l);
+                }
+                Node::If(c, vec![Node::Break], vec![])
+            },
+            Node::If(c, a, b) => Node::If(c, breaks(a, exit, halts, refs), breaks(b, exit, halts, refs)),
+            other => other,
+        })
+        .collect()
+}
+
+fn halts(nodes : &[Node]) -> bool {
+    nodes.iter().any(|node| match node {
+        Node::Goto(Target::Halt) | Node::CondGoto(_, Target::Halt) => true,
+        Node::If(_, a, b) => halts(a) || halts(b),
+        _ => false,
+    })
+}
+
+fn find_loop(nodes : &mut Vec<Node>, refs : &mut HashMap<usize, usize>) -> bool {
+    for i in 0..nodes.len() {
+        let label = match nodes[i] {
+            Node::Label(l) => l,
+            _ => continue,
+        };
+
+        let j = match (i + 1..nodes.len()).rev().find(|&j| jump_target(&nodes[j]) == Some(label)) {
+            Some(j) => j,
+            None => continue,
+        };
+
+        let mut inside = HashMap::new();
+        count_refs(&nodes[i + 1..=j], &mut inside);
+        if inside.get(&label) != Some(&1) || !self_contained(&nodes[i + 1..=j], refs) {
+            continue;
+        }
+
+        let exit = labels_at(nodes, j + 1);
+        let latch = nodes.remove(j);
+        let body : Vec<Node> = nodes.drain(i + 1..j).collect();
+        release(refs, label);
+
+        let structured = match latch {
+            Node::CondGoto(c, _) => Node::DoWhile(structure(breaks(body, &exit, false, refs), refs), c),
+            _ => {
+                // An endless loop left only by halting reads better as a loop followed by `halt`.
+                let leaves_by_halting = halts(&body) && exit.iter().all(|l| !inside.contains_key(l));
+                if leaves_by_halting {
+                    nodes.insert(i + 1, Node::Goto(Target::Halt));
+                }
+
+                let body = structure(breaks(body, &exit, leaves_by_halting, refs), refs);
+                let mut body = drop_unused_labels(body, refs);
+                match body.first() {
+                    Some(Node::If(c, then_branch, else_branch)) if *then_branch == [Node::Break] && else_branch.is_empty() => {
+                        let condition = c.clone().negate();
+                        body.remove(0);
+                        Node::While(condition, body)
+                    },
+                    _ => Node::Loop(body),
+                }
+            },
+        };
+
+        nodes.insert(i + 1, structured);
+        return true;
+    }
+
+    false
+}
+
+fn find_if(nodes : &mut Vec<Node>, refs : &mut HashMap<usize, usize>) -> bool {
+    for i in 0..nodes.len() {
+        let x = match &nodes[i] {
+            Node::CondGoto(_, Target::Label(x)) => *x,
+            _ => continue,
+        };
+
+        let j = match label_position(nodes, x) {
+            Some(j) if j > i => j,
+            _ => continue,
+        };
+
+        if !self_contained(&nodes[i + 1..j], refs) {
+            continue;
+        }
+
+        // `if (c) goto X; A; goto Y; X: B; Y:` is an if/else.
+        let else_end = match nodes[j - 1] {
+            Node::Goto(Target::Label(y)) if j - 1 > i && refs.get(&x) == Some(&1) => match label_position(nodes, y) {
+                Some(k) if k > j && self_contained(&nodes[j + 1..k], refs) => Some((y, k)),
+                _ => None,
+            },
+            _ => None,
+        };
+
+        let condition = match &nodes[i] {
+            Node::CondGoto(c, _) => c.clone().negate(),
+            _ => unreachable!(),
+        };
+        release(refs, x);
+
+        let structured = match else_end {
+            Some((y, k)) => {
+                release(refs, y);
+                let else_branch : Vec<Node> = nodes.drain(j + 1..k).collect();
+                let then_branch : Vec<Node> = nodes.drain(i + 1..j - 1).collect();
+                nodes.drain(i..i + 2);
+                Node::If(condition, structure(then_branch, refs), structure(else_branch, refs))
+            },
+            None => {
+                let then_branch : Vec<Node> = nodes.drain(i + 1..j).collect();
+                nodes.remove(i);
+                Node::If(condition, structure(then_branch, refs), vec![])
+            },
+        };
+
+        nodes.insert(i, structured);
+        return true;
+    }
+
+    false
+}
+
+fn structure(mut nodes : Vec<Node>, refs : &mut HashMap<usize, usize>) -> Vec<Node> {
+    loop {
+        simplify(&mut nodes, refs);
+
+        if !find_loop(&mut nodes, refs) && !find_if(&mut nodes, refs) {
+            break;
+        }
+    }
+
+    nodes
+}
+
+fn drop_unused_labels(nodes : Vec<Node>, refs : &HashMap<usize, usize>) -> Vec<Node> {
+    nodes
+        .into_iter()
+        .filter(|node| match node {
+            Node::Label(l) => refs.get(l).copied().unwrap_or(0) > 0,
+            _ => true,
+        })
+        .map(|node| match node {
+            Node::If(c, a, b) => Node::If(c, drop_unused_labels(a, refs), drop_unused_labels(b, refs)),
+            Node::While(c, body) => Node::While(c, drop_unused_labels(body, refs)),
+            Node::DoWhile(body, c) => Node::DoWhile(drop_unused_labels(body, refs), c),
+            Node::Loop(body) => Node::Loop(drop_unused_labels(body, refs)),
+            other => other,
+        })
+        .collect()
+}
+
+fn render(nodes : &[Node], depth : usize, out : &mut String) {
+    let indent = "    ".repeat(depth);
+
+    nodes.iter().for_each(|node|{
+        match node {
+            Node::Label(l) => writeln!(out, "{}L{}:", "    ".repeat(depth.saturating_sub(1)), l),
+            Node::Assign(r, Expr::Bin(op, a, b)) if **a == Expr::Reg(*r) && op.is_associative() => writeln!(out, "{}r{} {}= {};", indent, r, op.symbol(), b),
+            Node::Assign(r, e) => writeln!(out, "{}r{} = {};", indent, r, e),
+            Node::Goto(Target::Label(l)) => writeln!(out, "{}goto L{};", indent, l),
+            Node::Goto(Target::Halt) => writeln!(out, "{}halt;", indent),
+            Node::CondGoto(c, Target::Label(l)) => writeln!(out, "{}if ({}) goto L{};", indent, c, l),
+            Node::CondGoto(c, Target::Halt) => writeln!(out, "{}if ({}) halt;", indent, c),
+            Node::IndirectJump(e) => writeln!(out, "{}goto *({});", indent, e),
+            Node::Break => writeln!(out, "{}break;", indent),
+            Node::If(c, a, b) => {
+                writeln!(out, "{}if ({}) {{", indent, c).unwrap();
+                render(a, depth + 1, out);
+                if !b.is_empty() {
+                    writeln!(out, "{}}} else {{", indent).unwrap();
+                    render(b, depth + 1, out);
+                }
+                writeln!(out, "{}}}", indent)
+            },
+            Node::While(c, body) => {
+                writeln!(out, "{}while ({}) {{", indent, c).unwrap();
+                render(body, depth + 1, out);
+                writeln!(out, "{}}}", indent)
+            },
+            Node::DoWhile(body, c) => {
+                writeln!(out, "{}do {{", indent).unwrap();
+                render(body, depth + 1, out);
+                writeln!(out, "{}}} while ({});", indent, c)
+            },
+            Node::Loop(body) => {
+                writeln!(out, "{}loop {{", indent).unwrap();
+                render(body, depth + 1, out);
+                writeln!(out, "{}}}", indent)
+            },
+        }
+        .unwrap();
+    });
+}
+
+pub fn decompile_nodes(program : &Program) -> Vec<Node> {
+    decompile_nodes_in(program, OpcodeRegistry::builtin())
+}
+
+pub fn decompile_nodes_in(program : &Program, registry : &OpcodeRegistry) -> Vec<Node> {
+    let mut refs = HashMap::new();
+    let nodes = lift_in(program, registry);
+    count_refs(&nodes, &mut refs);
+
+    // Labels nothing jumps to only get in the way of pattern matching.
+    let nodes = drop_unused_labels(nodes, &refs);
+
+    let nodes = structure(nodes, &mut refs);
+    drop_unused_labels(nodes, &refs)
+}
+
+pub fn decompile(program : &Program) -> String {
+    decompile_in(program, OpcodeRegistry::builtin())
+}
+
+// As `decompile`, for programs using the registry's custom opcodes.
+pub fn decompile_in(program : &Program, registry : &OpcodeRegistry) -> String {
+    let mut out = String::new();
+    render(&decompile_nodes_in(program, registry), 0, &mut out);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::assembler::assemble;
+
+    #[test]
+    fn decompile_while_loop_with_if() {
+
+        let program = assemble("#ip 4
+                                seti 0 0 1
+                                gtri 1 9 3
+                                addr 3 4 4
+                                addi 4 1 4
+                                seti 100 0 4
+                                bani 1 1 3
+                                eqri 3 0 3
+                                addr 3 4 4
+                                addi 4 1 4
+                                addr 0 1 0
+                                addi 1 1 1
+                                seti 0 0 4").unwrap();
+
+        assert_eq!(decompile(&program), "\
+r1 = 0;
+while (r1 <= 9) {
+    if ((r1 & 1) == 0) {
+        r0 += r1;
+    }
+    r1 += 1;
+}
+halt;
+");
+    }
+
+    #[test]
+    fn decompile_do_while_and_indirect_jump() {
+
+        let program = assemble("#ip 2
+                                addi 1 2 1
+                                mulr 1 1 3
+                                gtri 3 50 3
+                                addr 3 2 2
+                                seti -1 0 2
+                                seti 0 0 3
+                                addr 2 0 2
+                                seti 7 0 0").unwrap();
+
+        assert_eq!(decompile(&program), "\
+do {
+    r1 += 2;
+} while (r1 * r1 <= 50);
+r3 = 0;
+goto *(r0 + 7);
+r0 = 7;
+halt;
+");
+    }
+
+    #[test]
+    fn folding_keeps_repeated_and_clobbered_reads() {
+
+        let program = assemble("seti 3 0 2\nmulr 2 2 0").unwrap();
+        assert_eq!(decompile(&program), "r2 = 3;\nr0 = r2 * r2;\nhalt;\n");
+
+        let program = assemble("addi 1 5 3\naddr 3 3 0").unwrap();
+        assert_eq!(decompile(&program), "r3 = r1 + 5;\nr0 = r3 + r3;\nhalt;\n");
+
+        let program = assemble("addi 1 0 2\naddi 2 5 3\nseti 0 0 1\naddr 3 1 0\naddr 0 1 0").unwrap();
+        assert_eq!(decompile(&program), "r3 = r1 + 5;\nr1 = 0;\nr0 = r3 + r1 + r1;\nhalt;\n");
+    }
+}
@@ -0,0 +1,104 @@
+use super::registry::OpcodeRegistry;
+use super::{Instruction, InstructionSet, Mode, OpcodeMapping};
+use std::fmt::Write;
+
+fn operand(mode : Mode, value : i64) -> String {
+    match mode {
+        Mode::Register => format!("r{}", value),
+        Mode::Immediate => value.to_string(),
+        Mode::Ignored => String::from("_"),
+    }
+}
+
+// Writes registers as `rN` and ignored operands as `_`, e.g. `seti 5 _ r1`.
+fn annotate(instruction : &Instruction, registry : &OpcodeRegistry) -> String {
+    let (mode_a, mode_b) = registry.modes(instruction.opcode);
+    let name = registry.display_name(instruction.opcode);
+
+    format!("{} {} {} {}", name, operand(mode_a, instruction.a), operand(mode_b, instruction.b), operand(Mode::Register, instruction.c))
+}
+
+fn listing<F : Fn(&Instruction) -> String>(program : &[InstructionSet], mapping : &OpcodeMapping, format : F) -> String {
+    let width = program.len().saturating_sub(1).to_string().len();
+    let mut listing = String::new();
+
+    program
+        .iter()
+        .enumerate()
+        .for_each(|(idx, instruction)|{
+            match mapping.decode(instruction) {
+                Some(decoded) => writeln!(listing, "{:>width$}: {}", idx, format(&decoded), width = width),
+                None => writeln!(listing, "{:>width$}: {} ; unmapped opcode {}", idx, instruction, instruction.0, width = width),
+            }
+            .unwrap();
+        });
+
+    listing
+}
+
+// Renders one line per instruction, prefixed with its index in the program.
+// Opcode numbers missing from the mapping are kept numeric and flagged.
+// Without the index prefixes, the listing assembles back to the same program.
+pub fn disassemble(program : &[InstructionSet], mapping : &OpcodeMapping) -> String {
+    disassemble_in(program, mapping, OpcodeRegistry::builtin())
+}
+
+// As `disassemble`, taking the names of custom opcodes from the registry.
+pub fn disassemble_in(program : &[InstructionSet], mapping : &OpcodeMapping, registry : &OpcodeRegistry) -> String {
+    listing(program, mapping, |instruction| registry.format_instruction(instruction))
+}
+
+// As `disassemble`, but with operands marked by mode, e.g. `seti 5 _ r1`. Meant for reading;
+// the assembler only takes the plain form.
+pub fn disassemble_annotated(program : &[InstructionSet], mapping : &OpcodeMapping) -> String {
+    disassemble_annotated_in(program, mapping, OpcodeRegistry::builtin())
+}
+
+// As `disassemble_annotated`, taking names and operand modes of custom opcodes from the registry.
+pub fn disassemble_annotated_in(program : &[InstructionSet], mapping : &OpcodeMapping, registry : &OpcodeRegistry) -> String {
+    listing(program, mapping, |instruction| annotate(instruction, registry))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::assembler::assemble;
+    use super::super::generator::PuzzleGenerator;
+    use super::super::Opcodes;
+
+    #[test]
+    fn disassemble_with_mapping() {
+
+        let mapping = OpcodeMapping::new(vec![Opcodes::seti, Opcodes::addr, Opcodes::gtir]);
+        let program : Vec<InstructionSet> = ["0 5 0 1", "1 1 1 0", "7 1 2 3", "2 4 2 3"]
+            .iter()
+            .map(|&line| InstructionSet::from(line))
+            .collect();
+
+        assert_eq!(disassemble(&program, &mapping), "0: seti 5 0 1\n1: addr 1 1 0\n2: 7 1 2 3 ; unmapped opcode 7\n3: gtir 4 2 3\n");
+        assert_eq!(disassemble_annotated(&program, &mapping), "0: seti 5 _ r1\n1: addr r1 r1 r0\n2: 7 1 2 3 ; unmapped opcode 7\n3: gtir 4 r2 r3\n");
+    }
+
+    #[test]
+    fn disassemble_custom_opcode_modes() {
+
+        let mut registry = OpcodeRegistry::new();
+        let (_, subi) = registry.register_binary("sub", |a, b, overflow| overflow.sub(a, b)).unwrap();
+        let mapping = OpcodeMapping::new(vec![subi]);
+
+        assert_eq!(disassemble_in(&[InstructionSet::from("0 1 7 2")], &mapping, &registry), "0: subi 1 7 2\n");
+        assert_eq!(disassemble_annotated_in(&[InstructionSet::from("0 1 7 2")], &mapping, &registry), "0: subi r1 7 r2\n");
+    }
+
+    #[test]
+    fn disassembly_assembles_back() {
+
+        let mut generator = PuzzleGenerator::random(20);
+        let program = generator.program(200);
+        let listing = disassemble(&program, generator.mapping());
+
+        let source : Vec<&str> = listing.lines().map(|line| line.split_once(": ").unwrap().1).collect();
+        let assembled = assemble(&source.join("\n")).unwrap();
+        assert_eq!(Some(assembled), generator.mapping().decode_program(&program));
+    }
+}
@@ -0,0 +1,331 @@
+use super::registry::OpcodeRegistry;
+use super::{ApplyError, Instruction, Overflow, Program, Register};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmError {
+    BadRegister { register : i64, instruction : Instruction, index : usize },
+    Overflow { instruction : Instruction, index : usize },
+    Undefined { instruction : Instruction, index : usize },
+    UnknownOpcode { instruction : Instruction, index : usize },
+    BadIpRegister { register : usize, size : usize },
+    RegisterCount { expected : usize, found : usize },
+}
+
+impl fmt::Display for VmError {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::BadRegister { register, instruction, index } => write!(f, "instruction {} (`{}`) uses register {}, which does not exist", index, instruction, register),
+            VmError::Overflow { instruction, index } => write!(f, "instruction {} (`{}`) overflowed", index, instruction),
+            VmError::Undefined { instruction, index } => write!(f, "instruction {} (`{}`) has no result for its operands", index, instruction),
+            VmError::UnknownOpcode { instruction, index } => write!(f, "instruction {} (`{}`) uses an opcode that is not registered here", index, instruction),
+            VmError::BadIpRegister { register, size } => write!(f, "the instruction pointer is bound to register {}, but there are only {} registers", register, size),
+            VmError::RegisterCount { expected, found } => write!(f, "the program was compiled for {} registers, but was given {}", expected, found),
+        }
+    }
+
+}
+
+impl Error for VmError {}
+
+// A machine state seen twice. Execution is deterministic, so from `start` on the program repeats
+// the same `length` steps forever.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cycle {
+    pub ip : i64,
+    pub register : Register,
+    pub start : u64,
+    pub length : u64,
+}
+
+// How a budgeted run ended. An exhausted budget leaves the machine where it stopped, so the run can
+// be resumed with more fuel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RunOutcome {
+    Halted(Register),
+    BudgetExhausted { ip : i64, register : Register },
+    Faulted(VmError),
+    Cycle(Cycle),
+}
+
+// Runs a program with an instruction pointer. When the program binds the pointer to a register,
+// the register is loaded with the pointer before each instruction and read back afterwards,
+// so instructions writing to it act as jumps.
+pub struct Machine<'a> {
+    program : &'a Program,
+    ip : i64,
+    register : Register,
+    steps : u64,
+    overflow : Overflow,
+    registry : &'a OpcodeRegistry,
+}
+
+impl<'a> Machine<'a> {
+    pub fn new(program : &'a Program, register : Register) -> Machine<'a> {
+        Machine { program, ip: 0, register, steps: 0, overflow: Overflow::default(), registry: OpcodeRegistry::builtin() }
+    }
+
+    // Arithmetic is checked unless chosen otherwise here.
+    pub fn with_overflow(mut self, overflow : Overflow) -> Machine<'a> {
+        self.overflow = overflow;
+        self
+    }
+
+    pub fn overflow(&self) -> Overflow {
+        self.overflow
+    }
+
+    // Only the built-in opcodes run unless the program's registry is given here.
+    pub fn with_registry(mut self, registry : &'a OpcodeRegistry) -> Machine<'a> {
+        self.registry = registry;
+        self
+    }
+
+    pub fn registry(&self) -> &'a OpcodeRegistry {
+        self.registry
+    }
+
+    pub fn ip(&self) -> i64 {
+        self.ip
+    }
+
+    // The number of instructions executed so far.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn register(&self) -> &Register {
+        &self.register
+    }
+
+    pub fn register_mut(&mut self) -> &mut Register {
+        &mut self.register
+    }
+
+    pub fn program(&self) -> &'a Program {
+        self.program
+    }
+
+    pub fn current_instruction(&self) -> Option<&'a Instruction> {
+        if self.is_halted() {
+            return None;
+        }
+
+        Some(&self.program.instructions[self.ip as usize])
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.ip < 0 || self.ip as usize >= self.program.instructions.len()
+    }
+
+    // Executes the instruction under the pointer. Returns false once the pointer has left the program.
+    // On error the machine is left on the faulting instruction and its target register is not written.
+    pub fn step(&mut self) -> Result<bool, VmError> {
+        if self.is_halted() {
+            return Ok(false);
+        }
+
+        let index = self.ip as usize;
+        let instruction = &self.program.instructions[index];
+        let (overflow, registry) = (self.overflow, self.registry);
+        let fault = |error : ApplyError| match error {
+            ApplyError::Register(error) => VmError::BadRegister { register: error.register, instruction: *instruction, index },
+            ApplyError::Overflow => VmError::Overflow { instruction: *instruction, index },
+            ApplyError::Undefined => VmError::Undefined { instruction: *instruction, index },
+            ApplyError::UnknownOpcode(_) => VmError::UnknownOpcode { instruction: *instruction, index },
+        };
+
+        if let Some(ip_register) = self.program.ip_register {
+            let size = self.register.len();
+            self.register
+                .set(ip_register, self.ip)
+                .map_err(|_| VmError::BadIpRegister { register: ip_register, size })?;
+            registry.apply(instruction, &mut self.register, overflow).map_err(fault)?;
+
+            self.ip = self.register.get(ip_register).unwrap();
+        }
+        else {
+            registry.apply(instruction, &mut self.register, overflow).map_err(fault)?;
+        }
+
+        // No instruction follows i64::MAX, so a pointer that would overflow halts the machine, just as
+        // the compiled form's wrapping pointer does.
+        self.ip = self.ip.checked_add(1).unwrap_or(i64::MIN);
+        self.steps += 1;
+        Ok(true)
+    }
+
+    pub fn run(&mut self) -> Result<&Register, VmError> {
+        while self.step()? {}
+
+        Ok(&self.register)
+    }
+
+    // Like `run`, but executes at most `fuel` instructions.
+    pub fn run_with_budget(&mut self, fuel : u64) -> RunOutcome {
+        for _ in 0..fuel {
+            match self.step() {
+                Ok(true) => {},
+                Ok(false) => break,
+                Err(error) => return RunOutcome::Faulted(error),
+            }
+        }
+
+        if self.is_halted() {
+            RunOutcome::Halted(self.register.clone())
+        }
+        else {
+            RunOutcome::BudgetExhausted { ip: self.ip, register: self.register.clone() }
+        }
+    }
+
+    // Like `run_with_budget`, but remembers every (ip, registers) state and stops at the first one
+    // that repeats. Memory grows with the number of steps, so keep the budget modest.
+    pub fn run_detecting_cycles(&mut self, fuel : u64) -> RunOutcome {
+        let mut seen : HashMap<(i64, Register), u64> = HashMap::new();
+
+        for _ in 0..fuel {
+            if let Some(&start) = seen.get(&(self.ip, self.register.clone())) {
+                return RunOutcome::Cycle(Cycle { ip: self.ip, register: self.register.clone(), start, length: self.steps - start });
+            }
+            seen.insert((self.ip, self.register.clone()), self.steps);
+
+            match self.step() {
+                Ok(true) => {},
+                Ok(false) => break,
+                Err(error) => return RunOutcome::Faulted(error),
+            }
+        }
+
+        if self.is_halted() {
+            RunOutcome::Halted(self.register.clone())
+        }
+        else {
+            RunOutcome::BudgetExhausted { ip: self.ip, register: self.register.clone() }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::assembler::assemble;
+    use super::super::compiled::CompiledProgram;
+
+    #[test]
+    fn run_without_ip_binding() {
+
+        let program = assemble("seti 7 0 0\naddi 0 1 1").unwrap();
+        let mut machine = Machine::new(&program, Register::from("0, 0, 0, 0"));
+
+        assert_eq!(*machine.run().unwrap(), Register::from("7, 8, 0, 0"));
+        assert_eq!(machine.ip(), 2);
+    }
+
+    #[test]
+    fn run_with_ip_jumps() {
+
+        // Counts r1 up to 5 by jumping back through the bound register r3.
+        let program = assemble("#ip 3
+                                seti 0 0 1
+                                addi 1 1 1
+                                eqri 1 5 2
+                                addr 2 3 3
+                                seti 0 0 3
+                                seti 9 0 0").unwrap();
+        let mut machine = Machine::new(&program, Register::from("0, 0, 0, 0"));
+
+        assert_eq!(*machine.run().unwrap(), Register::from("9, 5, 1, 5"));
+        assert!(machine.is_halted());
+        assert_eq!(machine.step(), Ok(false));
+    }
+
+    #[test]
+    fn run_reports_bad_register() {
+
+        let program = assemble("seti 1 0 0\naddr 0 4 1\nseti 2 0 0").unwrap();
+        let mut machine = Machine::new(&program, Register::from("0, 0, 0, 0"));
+
+        assert_eq!(machine.run(), Err(VmError::BadRegister { register: 4, instruction: program.instructions[1], index: 1 }));
+        assert_eq!(machine.ip(), 1);
+        assert_eq!(*machine.register(), Register::from("1, 0, 0, 0"));
+
+        let program = assemble("#ip 4\nseti 1 0 0").unwrap();
+        let mut machine = Machine::new(&program, Register::from("0, 0, 0, 0"));
+
+        assert_eq!(machine.run(), Err(VmError::BadIpRegister { register: 4, size: 4 }));
+    }
+
+    #[test]
+    fn run_with_budget_outcomes() {
+
+        let program = assemble("#ip 2\naddi 0 1 0\nseti -1 0 2").unwrap();
+        let mut machine = Machine::new(&program, Register::new(3));
+
+        assert_eq!(machine.run_with_budget(5), RunOutcome::BudgetExhausted { ip: 1, register: Register::from("3, 0, 0") });
+        assert_eq!(machine.steps(), 5);
+        assert_eq!(machine.run_with_budget(1), RunOutcome::BudgetExhausted { ip: 0, register: Register::from("3, 0, -1") });
+
+        let program = assemble("seti 4 0 0\nmuli 0 2 1").unwrap();
+        let mut machine = Machine::new(&program, Register::new(2));
+        assert_eq!(machine.run_with_budget(2), RunOutcome::Halted(Register::from("4, 8")));
+        assert_eq!(machine.run_with_budget(2), RunOutcome::Halted(Register::from("4, 8")));
+
+        let program = assemble("seti 4 0 5").unwrap();
+        let mut machine = Machine::new(&program, Register::new(2));
+        assert_eq!(machine.run_with_budget(10), RunOutcome::Faulted(VmError::BadRegister { register: 5, instruction: program.instructions[0], index: 0 }));
+    }
+
+    #[test]
+    fn overflow_modes() {
+
+        let program = assemble("seti 2 0 0\nmuli 0 4611686018427387904 0\naddi 0 1 1").unwrap();
+
+        let mut machine = Machine::new(&program, Register::new(2));
+        assert_eq!(machine.run(), Err(VmError::Overflow { instruction: program.instructions[1], index: 1 }));
+
+        let mut machine = Machine::new(&program, Register::new(2)).with_overflow(Overflow::Wrapping);
+        assert_eq!(*machine.run().unwrap(), Register(vec![i64::MIN, i64::MIN + 1]));
+
+        let mut machine = Machine::new(&program, Register::new(2)).with_overflow(Overflow::Saturating);
+        assert_eq!(*machine.run().unwrap(), Register(vec![i64::MAX, i64::MAX]));
+    }
+
+    #[test]
+    fn detects_repeated_state() {
+
+        // Counts r0 modulo 4 forever.
+        let program = assemble("#ip 1
+                                seti 3 0 0
+                                addi 0 1 0
+                                bani 0 3 0
+                                seti 0 0 1").unwrap();
+        let mut machine = Machine::new(&program, Register::new(2));
+
+        assert_eq!(machine.run_detecting_cycles(100), RunOutcome::Cycle(Cycle { ip: 1, register: Register::from("3, 0"), start: 1, length: 12 }));
+        assert_eq!(machine.steps(), 13);
+
+        let program = assemble("seti 4 0 0").unwrap();
+        let mut machine = Machine::new(&program, Register::new(2));
+        assert_eq!(machine.run_detecting_cycles(100), RunOutcome::Halted(Register::from("4, 0")));
+    }
+
+    #[test]
+    fn pointer_overflow_halts() {
+
+        for (source, overflow) in [("#ip 0\nseti 9223372036854775807 0 0", Overflow::Checked), ("#ip 0\naddi 0 9223372036854775807 0", Overflow::Saturating)].iter() {
+            let program = assemble(source).unwrap();
+            let mut machine = Machine::new(&program, Register::new(1)).with_overflow(*overflow);
+
+            assert_eq!(*machine.run().unwrap(), Register(vec![i64::MAX]));
+            assert!(machine.is_halted());
+
+            let mut register = Register::new(1);
+            assert_eq!(CompiledProgram::compile(&program, 1).unwrap().run(&mut register, *overflow), Ok(1));
+            assert_eq!(register, Register(vec![i64::MAX]));
+        }
+    }
+}
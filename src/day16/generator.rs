@@ -0,0 +1,163 @@
+use super::{InstructionSet, OpcodeMapping, Opcodes, Register, TestCase, POSSIBLE_INSTRUCTIONS};
+use std::error::Error;
+use std::fmt;
+
+// SplitMix64: tiny, seedable and good enough for puzzle data. Not for anything needing real randomness.
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed : u64) -> Rng {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // A number in 0..bound; the modulo bias is irrelevant for bounds this small.
+    pub fn below(&mut self, bound : u64) -> u64 {
+        self.next_u64() % bound
+    }
+}
+
+// A uniformly shuffled opcode numbering.
+pub fn random_mapping(rng : &mut Rng) -> OpcodeMapping {
+    let mut opcodes = POSSIBLE_INSTRUCTIONS.to_vec();
+    for i in (1..opcodes.len()).rev() {
+        opcodes.swap(i, rng.below(i as u64 + 1) as usize);
+    }
+    OpcodeMapping::new(opcodes)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeneratorError {
+    EmptyMapping,
+    // Samples are computed without a registry, so only built-in opcodes can be generated.
+    CustomOpcode(Opcodes),
+}
+
+impl fmt::Display for GeneratorError {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GeneratorError::EmptyMapping => write!(f, "the mapping has no opcodes to generate"),
+            GeneratorError::CustomOpcode(opcode) => write!(f, "opcode {} is not built in and cannot be generated", opcode),
+        }
+    }
+
+}
+
+impl Error for GeneratorError {}
+
+// Produces puzzle inputs in the day 16 format for a known mapping: samples over four registers
+// holding 0 to 3, then a test program.
+pub struct PuzzleGenerator {
+    mapping : OpcodeMapping,
+    rng : Rng,
+}
+
+impl PuzzleGenerator {
+    pub fn new(mapping : OpcodeMapping, seed : u64) -> Result<PuzzleGenerator, GeneratorError> {
+        if let Some(&opcode) = mapping.0.iter().find(|opcode| matches!(opcode, Opcodes::Custom(_))) {
+            return Err(GeneratorError::CustomOpcode(opcode));
+        }
+        if mapping.0.is_empty() {
+            return Err(GeneratorError::EmptyMapping);
+        }
+
+        Ok(PuzzleGenerator { mapping, rng: Rng::new(seed) })
+    }
+
+    // A generator whose mapping is itself drawn from the seed.
+    pub fn random(seed : u64) -> PuzzleGenerator {
+        let mut rng = Rng::new(seed);
+        let mapping = random_mapping(&mut rng);
+        PuzzleGenerator { mapping, rng }
+    }
+
+    pub fn mapping(&self) -> &OpcodeMapping {
+        &self.mapping
+    }
+
+    // Numbers are drawn only from those the mapping covers, so decoding them cannot fail.
+    fn instruction(&mut self) -> InstructionSet {
+        let number = self.rng.below(self.mapping.0.len() as u64) as i64;
+        InstructionSet(number, self.rng.below(4) as i64, self.rng.below(4) as i64, self.rng.below(4) as i64)
+    }
+
+    pub fn sample(&mut self) -> TestCase {
+        let before : Vec<i64> = (0..4).map(|_| self.rng.below(4) as i64).collect();
+        let before = Register(before);
+        let instruction = self.instruction();
+
+        let mut after = before.clone();
+        self.mapping.decode(&instruction).unwrap().apply(&mut after).unwrap();
+
+        TestCase { before_register: before, instruction, after_register: after }
+    }
+
+    // A straight-line program that runs from all-zero registers without overflowing.
+    pub fn program(&mut self, len : usize) -> Vec<InstructionSet> {
+        let mut register = Register::new(4);
+        let mut program = Vec::with_capacity(len);
+
+        while program.len() < len {
+            let instruction = self.instruction();
+            let mut next = register.clone();
+
+            // Checked arithmetic refuses instructions that would overflow; draw another one instead.
+            if self.mapping.decode(&instruction).unwrap().apply(&mut next).is_ok() {
+                register = next;
+                program.push(instruction);
+            }
+        }
+
+        program
+    }
+
+    // A whole puzzle input: the samples, three blank lines, then the program.
+    pub fn input(&mut self, samples : usize, program_len : usize) -> String {
+        let samples : Vec<String> = (0..samples).map(|_| self.sample().to_string()).collect();
+        let program : Vec<String> = self.program(program_len).iter().map(|x| x.to_string()).collect();
+
+        format!("{}\n\n\n\n{}\n", samples.join("\n\n"), program.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::solver::{solve_mapping, MappingSolution};
+    use super::super::{input_generator_part1, input_generator_part2};
+
+    #[test]
+    fn generated_input_round_trips() {
+
+        let mut generator = PuzzleGenerator::random(16);
+        let input = generator.input(800, 50);
+
+        assert_eq!(input, PuzzleGenerator::random(16).input(800, 50));
+        assert_eq!(input_generator_part1(&input).len(), 800);
+
+        let (test_cases, program) = input_generator_part2(&input);
+        assert_eq!(program.len(), 50);
+        assert_eq!(solve_mapping(&test_cases), MappingSolution::Complete(generator.mapping().clone()));
+    }
+
+    #[test]
+    fn partial_mappings_generate_and_custom_ones_are_rejected() {
+
+        let mapping = OpcodeMapping::new(vec![Opcodes::addr, Opcodes::seti]);
+        let input = PuzzleGenerator::new(mapping, 1).unwrap().input(5, 5);
+        assert!(input_generator_part2(&input).0.iter().all(|sample| sample.instruction.0 < 2));
+
+        assert_eq!(PuzzleGenerator::new(OpcodeMapping::new(vec![Opcodes::seti, Opcodes::Custom(0)]), 1).err(),
+                   Some(GeneratorError::CustomOpcode(Opcodes::Custom(0))));
+        assert_eq!(PuzzleGenerator::new(OpcodeMapping::new(Vec::new()), 1).err(), Some(GeneratorError::EmptyMapping));
+    }
+}
@@ -0,0 +1,182 @@
+use super::{Opcodes, POSSIBLE_INSTRUCTIONS};
+use std::fmt;
+use std::iter::FromIterator;
+use std::ops::{BitAnd, BitAndAssign, BitOr};
+
+// A set of opcodes stored as one bit per opcode index: the built-ins in the order of
+// POSSIBLE_INSTRUCTIONS, then custom opcodes, up to CAPACITY in total.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct OpcodeSet(u64);
+
+impl OpcodeSet {
+    pub const CAPACITY : usize = 64;
+
+    pub fn empty() -> OpcodeSet {
+        OpcodeSet(0)
+    }
+
+    // The built-in opcodes; `OpcodeRegistry::all` also includes custom ones.
+    pub fn all() -> OpcodeSet {
+        OpcodeSet::first_n(POSSIBLE_INSTRUCTIONS.len())
+    }
+
+    // The opcodes with the indices 0..n.
+    pub fn first_n(n : usize) -> OpcodeSet {
+        if n >= OpcodeSet::CAPACITY {
+            OpcodeSet(u64::MAX)
+        }
+        else {
+            OpcodeSet((1 << n) - 1)
+        }
+    }
+
+    pub fn single(opcode : Opcodes) -> OpcodeSet {
+        OpcodeSet(1 << opcode.index())
+    }
+
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub fn contains(self, opcode : Opcodes) -> bool {
+        self.0 & (1 << opcode.index()) != 0
+    }
+
+    pub fn insert(&mut self, opcode : Opcodes) -> bool {
+        let absent = !self.contains(opcode);
+        self.0 |= 1 << opcode.index();
+        absent
+    }
+
+    pub fn remove(&mut self, opcode : Opcodes) -> bool {
+        let present = self.contains(opcode);
+        self.0 &= !(1 << opcode.index());
+        present
+    }
+
+    pub fn intersection(self, other : OpcodeSet) -> OpcodeSet {
+        OpcodeSet(self.0 & other.0)
+    }
+
+    pub fn difference(self, other : OpcodeSet) -> OpcodeSet {
+        OpcodeSet(self.0 & !other.0)
+    }
+
+    pub fn len(self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    // The lowest opcode in the set, or the only one for a singleton.
+    pub fn first(self) -> Option<Opcodes> {
+        if self.is_empty() {
+            None
+        }
+        else {
+            Some(Opcodes::from_index(self.0.trailing_zeros() as usize))
+        }
+    }
+
+    pub fn iter(self) -> Iter {
+        Iter(self.0)
+    }
+}
+
+pub struct Iter(u64);
+
+impl Iterator for Iter {
+    type Item = Opcodes;
+
+    fn next(&mut self) -> Option<Opcodes> {
+        if self.0 == 0 {
+            return None;
+        }
+
+        let idx = self.0.trailing_zeros() as usize;
+        self.0 &= self.0 - 1;
+        Some(Opcodes::from_index(idx))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.0.count_ones() as usize;
+        (len, Some(len))
+    }
+}
+
+impl IntoIterator for OpcodeSet {
+    type Item = Opcodes;
+    type IntoIter = Iter;
+
+    fn into_iter(self) -> Iter {
+        self.iter()
+    }
+}
+
+impl FromIterator<Opcodes> for OpcodeSet {
+    fn from_iter<I : IntoIterator<Item = Opcodes>>(iter : I) -> OpcodeSet {
+        let mut set = OpcodeSet::empty();
+        iter.into_iter().for_each(|opcode|{
+            set.insert(opcode);
+        });
+        set
+    }
+}
+
+impl BitAnd for OpcodeSet {
+    type Output = OpcodeSet;
+
+    fn bitand(self, other : OpcodeSet) -> OpcodeSet {
+        self.intersection(other)
+    }
+}
+
+impl BitAndAssign for OpcodeSet {
+    fn bitand_assign(&mut self, other : OpcodeSet) {
+        self.0 &= other.0;
+    }
+}
+
+impl BitOr for OpcodeSet {
+    type Output = OpcodeSet;
+
+    fn bitor(self, other : OpcodeSet) -> OpcodeSet {
+        OpcodeSet(self.0 | other.0)
+    }
+}
+
+impl fmt::Debug for OpcodeSet {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opcode_set_operations() {
+
+        let mut set : OpcodeSet = [Opcodes::addr, Opcodes::eqrr, Opcodes::seti].iter().copied().collect();
+
+        assert_eq!(set.len(), 3);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![Opcodes::addr, Opcodes::seti, Opcodes::eqrr]);
+        assert_eq!(set & OpcodeSet::single(Opcodes::seti), OpcodeSet::single(Opcodes::seti));
+        assert_eq!(OpcodeSet::all().len(), 16);
+
+        assert!(set.remove(Opcodes::addr));
+        assert!(!set.remove(Opcodes::addr));
+        assert_eq!(set.first(), Some(Opcodes::seti));
+        assert_eq!(format!("{:?}", set), "{seti, eqrr}");
+
+        set.insert(Opcodes::Custom(47));
+        assert_eq!(set.bits(), 1 << 63 | 1 << 15 | 1 << 9);
+        assert_eq!(set.iter().last(), Some(Opcodes::Custom(47)));
+        assert_eq!(OpcodeSet::first_n(64).len(), 64);
+    }
+}
@@ -0,0 +1,462 @@
+use super::{InstructionSet, Register, TestCase};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    BadBefore(String),
+    BadInstruction(String),
+    BadAfter(String),
+    WidthMismatch { before : usize, after : usize },
+    IncompleteSample,
+    ExtraLine(String),
+    Section(SectionError),
+}
+
+// A malformed line, numbered from 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub line : usize,
+    pub kind : ParseErrorKind,
+}
+
+impl fmt::Display for ParseError {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match &self.kind {
+            ParseErrorKind::BadBefore(line) => write!(f, "expected `Before: [a, b, c, d]`, found `{}`", line),
+            ParseErrorKind::BadInstruction(line) => write!(f, "expected four numbers, found `{}`", line),
+            ParseErrorKind::BadAfter(line) => write!(f, "expected `After:  [a, b, c, d]`, found `{}`", line),
+            ParseErrorKind::WidthMismatch { before, after } => write!(f, "{} registers before but {} after", before, after),
+            ParseErrorKind::IncompleteSample => write!(f, "sample ends before its `After:` line"),
+            ParseErrorKind::ExtraLine(line) => write!(f, "unexpected `{}` after a complete sample", line),
+            ParseErrorKind::Section(error) => write!(f, "{}", error),
+        }
+    }
+
+}
+
+impl Error for ParseError {}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Day16Input {
+    pub samples : Vec<TestCase>,
+    pub program : Vec<InstructionSet>,
+}
+
+fn parse_register(text : &str) -> Option<Register> {
+    let list = text.trim().strip_prefix('[')?.strip_suffix(']')?;
+
+    list.split(',')
+        .map(|value| value.trim().parse().ok())
+        .collect::<Option<Vec<i64>>>()
+        .map(Register)
+}
+
+fn parse_instruction(text : &str) -> Option<InstructionSet> {
+    let values = text
+        .split_whitespace()
+        .map(|value| value.parse().ok())
+        .collect::<Option<Vec<i64>>>()?;
+
+    match values[..] {
+        [number, a, b, c] => Some(InstructionSet(number, a, b, c)),
+        _ => None,
+    }
+}
+
+// Parses one blank-line separated block of `Before:`, instruction and `After:` lines.
+fn parse_sample(block : &[(usize, &str)]) -> Result<TestCase, ParseError> {
+    let error = |line, kind| ParseError { line, kind };
+    let line = |idx : usize| block.get(idx).copied().ok_or_else(|| error(block[block.len() - 1].0, ParseErrorKind::IncompleteSample));
+
+    let (before_line, before_text) = line(0)?;
+    let before_register = before_text.trim().strip_prefix("Before:")
+        .and_then(parse_register)
+        .ok_or_else(|| error(before_line, ParseErrorKind::BadBefore(before_text.to_string())))?;
+
+    let (instruction_line, instruction_text) = line(1)?;
+    let instruction = parse_instruction(instruction_text)
+        .ok_or_else(|| error(instruction_line, ParseErrorKind::BadInstruction(instruction_text.to_string())))?;
+
+    let (after_line, after_text) = line(2)?;
+    let after_register = after_text.trim().strip_prefix("After:")
+        .and_then(parse_register)
+        .ok_or_else(|| error(after_line, ParseErrorKind::BadAfter(after_text.to_string())))?;
+
+    if before_register.len() != after_register.len() {
+        return Err(error(after_line, ParseErrorKind::WidthMismatch { before: before_register.len(), after: after_register.len() }));
+    }
+
+    if let Some((extra_line, extra_text)) = block.get(3) {
+        return Err(error(*extra_line, ParseErrorKind::ExtraLine(extra_text.to_string())));
+    }
+
+    Ok(TestCase { before_register, instruction, after_register })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SectionError {
+    EmptySamples,
+    EmptyProgram,
+}
+
+impl fmt::Display for SectionError {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SectionError::EmptySamples => write!(f, "the input has no samples"),
+            SectionError::EmptyProgram => write!(f, "nothing follows the samples, the test program is missing"),
+        }
+    }
+
+}
+
+impl Error for SectionError {}
+
+// The input split where its sample blocks end, each line numbered from 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sections<'a> {
+    pub samples : Vec<(usize, &'a str)>,
+    pub program : Vec<(usize, &'a str)>,
+}
+
+fn is_blank(line : &str) -> bool {
+    line.trim().is_empty()
+}
+
+// Groups lines into runs separated by blank lines, however many and whatever whitespace they hold.
+fn blocks<'a>(lines : impl Iterator<Item = (usize, &'a str)>) -> Vec<Vec<(usize, &'a str)>> {
+    let mut blocks : Vec<Vec<(usize, &str)>> = vec![Vec::new()];
+
+    lines.for_each(|(line, text)|{
+        if is_blank(text) {
+            if !blocks.last().unwrap().is_empty() {
+                blocks.push(Vec::new());
+            }
+        }
+        else {
+            blocks.last_mut().unwrap().push((line, text));
+        }
+    });
+
+    blocks.retain(|block| !block.is_empty());
+    blocks
+}
+
+fn numbered_lines(input : &str) -> impl Iterator<Item = (usize, &str)> {
+    // `lines` also strips the `\r` of CRLF endings.
+    input.lines().enumerate().map(|(idx, line)| (idx + 1, line))
+}
+
+// Only sample lines carry `Before:`, `After:` and register lists, so a block holding any of them
+// belongs to the samples, even when malformed.
+fn is_sample_block(block : &[(usize, &str)]) -> bool {
+    block.iter().any(|(_, text)| text.contains(':') || text.contains('['))
+}
+
+// The samples run up to the end of the last sample block and the program is everything after,
+// however many blank lines separate the blocks.
+pub fn split_sections(input : &str) -> Result<Sections<'_>, SectionError> {
+    let lines : Vec<(usize, &str)> = numbered_lines(input).collect();
+    let sample_blocks : Vec<_> = blocks(lines.iter().copied()).into_iter().filter(|block| is_sample_block(block)).collect();
+
+    let (first, separator) = match (sample_blocks.first(), sample_blocks.last()) {
+        (Some(first), Some(last)) => (first[0].0 - 1, last[last.len() - 1].0),
+        _ => return Err(SectionError::EmptySamples),
+    };
+
+    let program : Vec<(usize, &str)> = lines[separator..].iter().filter(|(_, text)| !is_blank(text)).copied().collect();
+    if program.is_empty() {
+        return Err(SectionError::EmptyProgram);
+    }
+
+    Ok(Sections { samples: lines[first..separator].to_vec(), program })
+}
+
+fn parse_program_line(line : usize, text : &str) -> Result<InstructionSet, ParseError> {
+    parse_instruction(text).ok_or_else(|| ParseError { line, kind: ParseErrorKind::BadInstruction(text.to_string()) })
+}
+
+// Parses every block of the first section as a sample and every line of the second as an
+// instruction. Malformed blocks and lines are skipped and reported.
+fn parse_sections_lenient(sections : &Sections<'_>) -> (Day16Input, Vec<ParseError>) {
+    let mut parsed = Day16Input::default();
+    let mut errors = Vec::new();
+
+    blocks(sections.samples.iter().copied())
+        .iter()
+        .for_each(|block| match parse_sample(block) {
+            Ok(sample) => parsed.samples.push(sample),
+            Err(error) => errors.push(error),
+        });
+
+    sections.program
+        .iter()
+        .for_each(|&(line, text)| match parse_program_line(line, text) {
+            Ok(instruction) => parsed.program.push(instruction),
+            Err(error) => errors.push(error),
+        });
+
+    (parsed, errors)
+}
+
+// Parses already split sections; any malformed block or line fails the whole parse.
+pub fn parse_sections(sections : &Sections<'_>) -> Result<Day16Input, Vec<ParseError>> {
+    match parse_sections_lenient(sections) {
+        (parsed, errors) if errors.is_empty() => Ok(parsed),
+        (_, errors) => Err(errors),
+    }
+}
+
+// Splits the input with `split_sections`, then parses what it can. An input that cannot be split
+// is reported at its last line.
+pub fn parse_lenient(input : &str) -> (Day16Input, Vec<ParseError>) {
+    match split_sections(input) {
+        Ok(sections) => parse_sections_lenient(&sections),
+        Err(error) => (Day16Input::default(), vec![ParseError { line: input.lines().count(), kind: ParseErrorKind::Section(error) }]),
+    }
+}
+
+// Like `parse_lenient`, but any malformed block or line fails the whole parse.
+pub fn parse(input : &str) -> Result<Day16Input, Vec<ParseError>> {
+    match parse_lenient(input) {
+        (parsed, errors) if errors.is_empty() => Ok(parsed),
+        (_, errors) => Err(errors),
+    }
+}
+
+#[derive(Debug)]
+pub enum ReadError {
+    Io(io::Error),
+    Parse(ParseError),
+}
+
+impl From<io::Error> for ReadError {
+    fn from(error : io::Error) -> Self {
+        ReadError::Io(error)
+    }
+}
+
+impl From<ParseError> for ReadError {
+    fn from(error : ParseError) -> Self {
+        ReadError::Parse(error)
+    }
+}
+
+impl fmt::Display for ReadError {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadError::Io(error) => write!(f, "reading samples failed: {}", error),
+            ReadError::Parse(error) => write!(f, "{}", error),
+        }
+    }
+
+}
+
+impl Error for ReadError {
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ReadError::Io(error) => Some(error),
+            ReadError::Parse(error) => Some(error),
+        }
+    }
+
+}
+
+// Reads samples one block at a time, holding no more than a block in memory. Stops at the end of
+// the input or at the first block that is not a sample, as `split_sections` tells them apart, which
+// is where the test program begins. A malformed sample, header included, is yielded as an error
+// and reading carries on with the next one.
+pub struct SampleReader<R : BufRead> {
+    reader : R,
+    line : usize,
+    buffer : String,
+    done : bool,
+}
+
+impl<R : BufRead> SampleReader<R> {
+    pub fn new(reader : R) -> SampleReader<R> {
+        SampleReader { reader, line: 0, buffer: String::new(), done: false }
+    }
+
+    // The number of lines read so far.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    // The next line without its line ending, or None at the end of the input.
+    fn next_line(&mut self) -> io::Result<Option<String>> {
+        self.buffer.clear();
+        if self.reader.read_line(&mut self.buffer)? == 0 {
+            return Ok(None);
+        }
+
+        self.line += 1;
+        Ok(Some(self.buffer.trim_end_matches(&['\r', '\n'][..]).to_string()))
+    }
+
+    fn next_block(&mut self) -> io::Result<Option<Vec<(usize, String)>>> {
+        let mut block = Vec::new();
+
+        while let Some(text) = self.next_line()? {
+            match (is_blank(&text), block.is_empty()) {
+                (true, true) => continue,
+                (true, false) => break,
+                (false, _) => block.push((self.line, text)),
+            }
+        }
+
+        Ok(if block.is_empty() { None } else { Some(block) })
+    }
+}
+
+impl<R : BufRead> Iterator for SampleReader<R> {
+    type Item = Result<TestCase, ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        match self.next_block() {
+            Ok(Some(block)) => {
+                let block : Vec<(usize, &str)> = block.iter().map(|(line, text)| (*line, text.as_str())).collect();
+                if is_sample_block(&block) {
+                    return Some(parse_sample(&block).map_err(ReadError::from));
+                }
+
+                self.done = true;
+                None
+            },
+            Ok(None) => {
+                self.done = true;
+                None
+            },
+            Err(error) => {
+                self.done = true;
+                Some(Err(error.into()))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INPUT : &str = "Before: [3, 2, 1, 1]
+9 2 1 2
+After:  [3, 2, 2, 1]
+
+Before: [0, 1, 2, 3]
+4 1 2 3
+After:  [0, 1, 2, 0]
+
+
+
+7 3 2 0
+7 2 1 1
+";
+
+    #[test]
+    fn parse_samples_and_program() {
+
+        let parsed = parse(INPUT).unwrap();
+
+        assert_eq!(parsed.samples.len(), 2);
+        assert_eq!(parsed.samples[1].instruction, InstructionSet(4, 1, 2, 3));
+        assert_eq!(parsed.samples[1].after_register, Register::from("0, 1, 2, 0"));
+        assert_eq!(parsed.program, vec![InstructionSet(7, 3, 2, 0), InstructionSet(7, 2, 1, 1)]);
+        assert_eq!(parse(&INPUT.replace('\n', "\r\n")), Ok(parsed));
+    }
+
+    #[test]
+    fn malformed_blocks_are_reported_by_line() {
+
+        let input = INPUT.replace("4 1 2 3", "4 1 2").replace("After:  [3, 2, 2, 1]", "After:  [3, 2, 2, 1]\nBefore: [1, 1, 1, 1]") + "7 x 2 0\n";
+
+        let errors = parse(&input).unwrap_err();
+        assert_eq!(errors, vec![
+            ParseError { line: 4, kind: ParseErrorKind::ExtraLine(String::from("Before: [1, 1, 1, 1]")) },
+            ParseError { line: 7, kind: ParseErrorKind::BadInstruction(String::from("4 1 2")) },
+            ParseError { line: 14, kind: ParseErrorKind::BadInstruction(String::from("7 x 2 0")) },
+        ]);
+        assert_eq!(errors[1].to_string(), "line 7: expected four numbers, found `4 1 2`");
+
+        let (parsed, warnings) = parse_lenient(&input);
+        assert_eq!(parsed.samples.len(), 0);
+        assert_eq!(parsed.program.len(), 2);
+        assert_eq!(warnings, errors);
+
+        let (parsed, warnings) = parse_lenient("Before: [1, 2]\n0 0 0 0\n\n\n\n1 2 3 4\n");
+        assert!(parsed.samples.is_empty());
+        assert_eq!(warnings, vec![ParseError { line: 2, kind: ParseErrorKind::IncompleteSample }]);
+    }
+
+    #[test]
+    fn lenient_parse_skips_a_bad_sample_and_keeps_going() {
+
+        let input = format!("{}\n\n{}", INPUT.replace("Before: [0", "Befor: [0").split("\n\n\n\n").next().unwrap(), INPUT);
+
+        let (parsed, warnings) = parse_lenient(&input);
+        assert_eq!(parsed.samples.len(), 3);
+        assert_eq!(parsed.samples[1..], parse(INPUT).unwrap().samples[..]);
+        assert_eq!(parsed.program, parse(INPUT).unwrap().program);
+        assert_eq!(warnings, vec![ParseError { line: 5, kind: ParseErrorKind::BadBefore(String::from("Befor: [0, 1, 2, 3]")) }]);
+    }
+
+    #[test]
+    fn split_sections_tolerates_line_endings_and_blank_runs() {
+
+        let messy = INPUT.replace("\n\n\n\n", "\n  \n\t\n\n\n\n").replace('\n', "\r\n") + "\r\n\r\n";
+        let sections = split_sections(&messy).unwrap();
+
+        assert_eq!(sections.samples.len(), 7);
+        assert_eq!(sections.program, vec![(13, "7 3 2 0"), (14, "7 2 1 1")]);
+        assert_eq!(parse_sections(&sections), parse(INPUT));
+
+        assert_eq!(split_sections(" \n\n"), Err(SectionError::EmptySamples));
+        assert_eq!(split_sections("Before: [0]\n0 0 0 0\nAfter:  [0]\n\n\n\n"), Err(SectionError::EmptyProgram));
+
+        let stray = INPUT.replacen("\n\n", "\n\n\n", 1);
+        assert_eq!(split_sections(&stray).unwrap().program, vec![(12, "7 3 2 0"), (13, "7 2 1 1")]);
+        assert_eq!(parse(&stray), parse(INPUT));
+    }
+
+    #[test]
+    fn reader_streams_samples() {
+
+        let input = INPUT.replace("9 2 1 2", "9 2 1").replace('\n', "\r\n");
+        let mut reader = SampleReader::new(input.as_bytes());
+
+        match reader.next() {
+            Some(Err(ReadError::Parse(error))) => assert_eq!(error, ParseError { line: 2, kind: ParseErrorKind::BadInstruction(String::from("9 2 1")) }),
+            other => panic!("Unexpected item {:?}", other),
+        }
+        assert_eq!(reader.next().unwrap().unwrap(), parse(INPUT).unwrap().samples[1]);
+        assert!(reader.next().is_none());
+        assert_eq!(reader.line(), 12);
+    }
+
+    #[test]
+    fn reader_reports_a_corrupted_header_mid_stream() {
+
+        let input = format!("{}\n\n{}", INPUT.split("\n\n\n\n").next().unwrap(), INPUT.replace("Before: [3", "Befor: [3"));
+        let samples = parse(INPUT).unwrap().samples;
+        let mut reader = SampleReader::new(input.as_bytes());
+
+        assert_eq!(reader.next().unwrap().unwrap(), samples[0]);
+        assert_eq!(reader.next().unwrap().unwrap(), samples[1]);
+        match reader.next() {
+            Some(Err(ReadError::Parse(error))) => assert_eq!(error, ParseError { line: 9, kind: ParseErrorKind::BadBefore(String::from("Befor: [3, 2, 1, 1]")) }),
+            other => panic!("Unexpected item {:?}", other),
+        }
+        assert_eq!(reader.next().unwrap().unwrap(), samples[1]);
+        assert!(reader.next().is_none());
+    }
+}
@@ -0,0 +1,321 @@
+use super::opcode_set::OpcodeSet;
+use super::executor::VmError;
+use super::{ApplyError, Instruction, InstructionSet, Mode, Opcodes, Overflow, Program, Register, TestCase, POSSIBLE_INSTRUCTIONS};
+use std::error::Error;
+use std::fmt;
+
+// The semantics of one opcode: read what it needs from the registers given its operands, then
+// write its result to register `c`.
+pub trait Operation : Send + Sync {
+    fn name(&self) -> &str;
+
+    // The modes of operands a and b, registers unless stated otherwise.
+    fn modes(&self) -> (Mode, Mode) {
+        (Mode::Register, Mode::Register)
+    }
+
+    fn apply(&self, a : i64, b : i64, c : i64, register : &mut Register, overflow : Overflow) -> Result<(), ApplyError>;
+}
+
+// A two-operand operation over register `a` and either register `b` or the immediate `b`,
+// such as `subr`/`subi`. The function is given the run's overflow mode, see `Overflow::sub`.
+// A `None` result makes the instruction fail as undefined.
+pub struct Binary {
+    name : String,
+    b_is_register : bool,
+    function : fn(i64, i64, Overflow) -> Option<i64>,
+}
+
+impl Binary {
+    pub fn new(name : &str, b_is_register : bool, function : fn(i64, i64, Overflow) -> Option<i64>) -> Binary {
+        Binary { name: name.to_string(), b_is_register, function }
+    }
+}
+
+impl Operation for Binary {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn modes(&self) -> (Mode, Mode) {
+        (Mode::Register, if self.b_is_register { Mode::Register } else { Mode::Immediate })
+    }
+
+    fn apply(&self, a : i64, b : i64, c : i64, register : &mut Register, overflow : Overflow) -> Result<(), ApplyError> {
+        let b = if self.b_is_register { register.read(b)? } else { b };
+        let value = (self.function)(register.read(a)?, b, overflow).ok_or(ApplyError::Undefined)?;
+        register.write(c, value)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegistryError {
+    DuplicateName(String),
+    Full,
+}
+
+impl fmt::Display for RegistryError {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistryError::DuplicateName(name) => write!(f, "an opcode named `{}` is already registered", name),
+            RegistryError::Full => write!(f, "no more than {} opcodes can be registered", OpcodeSet::CAPACITY),
+        }
+    }
+
+}
+
+impl Error for RegistryError {}
+
+// The opcodes a dialect knows: always the 16 built-ins, plus any custom operations registered on
+// top. Registering hands out `Opcodes::Custom` values, which mean something only to this registry.
+#[derive(Default)]
+pub struct OpcodeRegistry {
+    custom : Vec<Box<dyn Operation>>,
+}
+
+static BUILTIN : OpcodeRegistry = OpcodeRegistry::new();
+
+impl OpcodeRegistry {
+    pub const fn new() -> OpcodeRegistry {
+        OpcodeRegistry { custom: Vec::new() }
+    }
+
+    // A shared registry holding only the built-ins.
+    pub fn builtin() -> &'static OpcodeRegistry {
+        &BUILTIN
+    }
+
+    pub fn register<O : Operation + 'static>(&mut self, operation : O) -> Result<Opcodes, RegistryError> {
+        if self.find(operation.name()).is_some() {
+            return Err(RegistryError::DuplicateName(operation.name().to_string()));
+        }
+        if self.len() >= OpcodeSet::CAPACITY {
+            return Err(RegistryError::Full);
+        }
+
+        self.custom.push(Box::new(operation));
+        Ok(Opcodes::Custom(self.custom.len() as u8 - 1))
+    }
+
+    // Registers the register and immediate forms of a binary operation as `<stem>r` and `<stem>i`.
+    pub fn register_binary(&mut self, stem : &str, function : fn(i64, i64, Overflow) -> Option<i64>) -> Result<(Opcodes, Opcodes), RegistryError> {
+        let register_form = self.register(Binary::new(&format!("{}r", stem), true, function))?;
+        let immediate_form = self.register(Binary::new(&format!("{}i", stem), false, function))?;
+        Ok((register_form, immediate_form))
+    }
+
+    pub fn len(&self) -> usize {
+        POSSIBLE_INSTRUCTIONS.len() + self.custom.len()
+    }
+
+    // Never true, the built-ins are always there.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    pub fn contains(&self, opcode : Opcodes) -> bool {
+        opcode.index() < self.len()
+    }
+
+    pub fn opcodes(&self) -> impl Iterator<Item = Opcodes> {
+        (0..self.len()).map(Opcodes::from_index)
+    }
+
+    pub fn all(&self) -> OpcodeSet {
+        OpcodeSet::first_n(self.len())
+    }
+
+    pub fn find(&self, name : &str) -> Option<Opcodes> {
+        Opcodes::from_name(name).or_else(||{
+            self.custom
+                .iter()
+                .position(|operation| operation.name() == name)
+                .map(|id| Opcodes::Custom(id as u8))
+        })
+    }
+
+    pub fn name(&self, opcode : Opcodes) -> Option<String> {
+        match opcode {
+            Opcodes::Custom(id) => self.custom.get(id as usize).map(|operation| operation.name().to_string()),
+            builtin => Some(builtin.to_string()),
+        }
+    }
+
+    // The name to show for an opcode, falling back to `custom{id}` for one this registry lacks.
+    pub fn display_name(&self, opcode : Opcodes) -> String {
+        self.name(opcode).unwrap_or_else(|| opcode.to_string())
+    }
+
+    // Like the `Display` of `Instruction`, but naming custom opcodes.
+    pub fn format_instruction(&self, instruction : &Instruction) -> String {
+        format!("{} {} {} {}", self.display_name(instruction.opcode), instruction.a, instruction.b, instruction.c)
+    }
+
+    pub fn modes(&self, opcode : Opcodes) -> (Mode, Mode) {
+        match opcode {
+            Opcodes::Custom(id) => self.custom.get(id as usize).map_or((Mode::Register, Mode::Register), |operation| operation.modes()),
+            builtin => builtin.modes(),
+        }
+    }
+
+    // The first operand naming a register outside a file of `register_count`, if any.
+    pub fn bad_register(&self, instruction : &Instruction, register_count : usize) -> Option<i64> {
+        let (mode_a, mode_b) = self.modes(instruction.opcode);
+        let in_range = |register : i64| register >= 0 && (register as usize) < register_count;
+
+        [(mode_a, instruction.a), (mode_b, instruction.b), (Mode::Register, instruction.c)]
+            .iter()
+            .find(|(mode, register)| mode.is_register() && !in_range(*register))
+            .map(|(_, register)| *register)
+    }
+
+    // Checks every register operand up front, rather than when execution reaches it.
+    pub fn validate(&self, program : &Program, register_count : usize) -> Result<(), VmError> {
+        if let Some(register) = program.ip_register.filter(|&r| r >= register_count) {
+            return Err(VmError::BadIpRegister { register, size: register_count });
+        }
+
+        program.instructions
+            .iter()
+            .enumerate()
+            .try_for_each(|(index, instruction)|{
+                if !self.contains(instruction.opcode) {
+                    return Err(VmError::UnknownOpcode { instruction: *instruction, index });
+                }
+
+                match self.bad_register(instruction, register_count) {
+                    Some(register) => Err(VmError::BadRegister { register, instruction: *instruction, index }),
+                    None => Ok(()),
+                }
+            })
+    }
+
+    pub fn apply(&self, instruction : &Instruction, register : &mut Register, overflow : Overflow) -> Result<(), ApplyError> {
+        match instruction.opcode {
+            Opcodes::Custom(id) => match self.custom.get(id as usize) {
+                Some(operation) => operation.apply(instruction.a, instruction.b, instruction.c, register, overflow),
+                None => Err(ApplyError::UnknownOpcode(instruction.opcode)),
+            },
+            _ => instruction.apply_with(register, overflow),
+        }
+    }
+
+    // The registered opcodes that turn the sample's before registers into its after registers.
+    // Opcodes that would read or write a register the sample does not have are ruled out directly.
+    pub fn matching_opcodes(&self, test_case : &TestCase) -> OpcodeSet {
+        let InstructionSet(_, a, b, c) = test_case.instruction;
+        let width = test_case.before_register.len();
+
+        self.opcodes()
+            .map(|opcode| Instruction { opcode, a, b, c })
+            .filter(|instruction| self.bad_register(instruction, width).is_none())
+            .filter(|instruction|{
+                let mut register_test = test_case.before_register.clone();
+                self.apply(instruction, &mut register_test, Overflow::Checked).is_ok() && test_case.after_register == register_test
+            })
+            .map(|instruction| instruction.opcode)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::assembler::assemble_in;
+    use super::super::debugger::Debugger;
+    use super::super::decompiler::decompile_in;
+    use super::super::trace::Trace;
+    use super::super::executor::{Machine, VmError};
+    use super::super::solver::{candidate_sets_in, consistent_mappings_in};
+
+    #[test]
+    fn register_custom_operations() {
+
+        let mut registry = OpcodeRegistry::new();
+        let (subr, subi) = registry.register_binary("sub", |a, b, overflow| overflow.sub(a, b)).unwrap();
+        let (divr, _) = registry.register_binary("div", |a, b, _| a.checked_div(b)).unwrap();
+
+        assert_eq!((subr, subi, divr), (Opcodes::Custom(0), Opcodes::Custom(1), Opcodes::Custom(2)));
+        assert_eq!(registry.len(), 20);
+        assert_eq!(registry.find("subi"), Some(subi));
+        assert_eq!(registry.find("addr"), Some(Opcodes::addr));
+        assert_eq!(registry.name(divr), Some(String::from("divr")));
+        assert_eq!(registry.register(Binary::new("subr", true, |a, b, overflow| overflow.sub(a, b))), Err(RegistryError::DuplicateName(String::from("subr"))));
+
+        let mut register = Register::from("7, 2, 0");
+        registry.apply(&Instruction { opcode: subi, a: 0, b: 3, c: 2 }, &mut register, Overflow::Checked).unwrap();
+        assert_eq!(register, Register::from("7, 2, 4"));
+        assert_eq!(registry.apply(&Instruction { opcode: divr, a: 0, b: 2, c: 2 }, &mut Register::from("7, 2, 0"), Overflow::Checked), Err(ApplyError::Undefined));
+        assert_eq!(OpcodeRegistry::builtin().apply(&Instruction { opcode: subi, a: 0, b: 3, c: 2 }, &mut register, Overflow::Checked), Err(ApplyError::UnknownOpcode(subi)));
+
+        let test_case = TestCase { before_register: Register::from("7, 2, 0"), instruction: InstructionSet(0, 0, 1, 2), after_register: Register::from("7, 2, 5") };
+        assert_eq!(registry.matching_opcodes(&test_case), OpcodeSet::single(subr));
+
+        let mut register = Register::from("-9223372036854775807, 2, 0");
+        let instruction = Instruction { opcode: subr, a: 0, b: 1, c: 2 };
+        assert_eq!(registry.apply(&instruction, &mut register, Overflow::Checked), Err(ApplyError::Undefined));
+        registry.apply(&instruction, &mut register, Overflow::Wrapping).unwrap();
+        assert_eq!(register.get(2), Some(i64::MAX));
+        registry.apply(&instruction, &mut register, Overflow::Saturating).unwrap();
+        assert_eq!(register.get(2), Some(i64::MIN));
+    }
+
+    #[test]
+    fn dialect_runs_through_assembler_executor_and_solver() {
+
+        let mut registry = OpcodeRegistry::new();
+        let (modr, modi) = registry.register_binary("mod", |a, b, _| a.checked_rem(b)).unwrap();
+
+        let program = assemble_in("seti 17 0 0\nmodi 0 5 1\nmodr 0 1 2", &registry).unwrap();
+        assert_eq!(program.instructions[1].opcode, modi);
+
+        let mut machine = Machine::new(&program, Register::new(3)).with_registry(&registry);
+        assert_eq!(*machine.run().unwrap(), Register::from("17, 2, 1"));
+
+        let mut machine = Machine::new(&program, Register::new(3));
+        assert_eq!(machine.run(), Err(VmError::UnknownOpcode { instruction: program.instructions[1], index: 1 }));
+
+        // 11 % 4 == 3 is only explained by modr, and ties opcode number 17 to it.
+        let test_case = TestCase { before_register: Register::from("11, 4, 0"), instruction: InstructionSet(17, 0, 1, 2), after_register: Register::from("11, 4, 3") };
+        let candidates = candidate_sets_in(&registry, std::slice::from_ref(&test_case)).unwrap();
+        assert_eq!(candidates.len(), 18);
+        assert_eq!(candidates[17], OpcodeSet::single(modr));
+        assert!(consistent_mappings_in(&registry, &[test_case], 5).mappings.iter().all(|mapping| mapping.get(17) == Some(modr)));
+    }
+
+    #[test]
+    fn custom_opcodes_are_shown_by_name() {
+
+        let mut registry = OpcodeRegistry::new();
+        registry.register_binary("mod", |a, b, _| a.checked_rem(b)).unwrap();
+        let program = assemble_in("seti 17 0 1\nmodi 1 5 0", &registry).unwrap();
+
+        let steps : Vec<_> = Trace::new(&program, Register::new(2)).with_registry(&registry).collect();
+        assert!(steps[1].to_json_in(&registry).starts_with("{\"index\":1,\"opcode\":\"modi\","));
+
+        let mut debugger = Debugger::new(&program, Register::new(2)).with_registry(&registry);
+        assert_eq!(debugger.execute("step"), Some(String::from("   1: modi 1 5 0")));
+
+        assert_eq!(decompile_in(&program, &registry), "r0 = modi(17, 5);\nhalt;\n");
+    }
+
+    #[test]
+    fn modes_rule_out_bad_registers() {
+
+        let registry = OpcodeRegistry::builtin();
+        assert_eq!(registry.modes(Opcodes::gtir), (Mode::Immediate, Mode::Register));
+
+        // Operand a is 7, so only opcodes taking it as an immediate can explain the sample.
+        let test_case = TestCase { before_register: Register::from("0, 1, 2, 3"), instruction: InstructionSet(0, 7, 3, 0), after_register: Register::from("7, 1, 2, 3") };
+        assert_eq!(registry.matching_opcodes(&test_case), OpcodeSet::single(Opcodes::seti));
+
+        let program = Program { ip_register: None, instructions: vec![
+            Instruction { opcode: Opcodes::seti, a: 7, b: 99, c: 0 },
+            Instruction { opcode: Opcodes::gtri, a: 7, b: 0, c: 0 },
+        ]};
+        assert_eq!(registry.validate(&program, 4), Err(VmError::BadRegister { register: 7, instruction: program.instructions[1], index: 1 }));
+        assert_eq!(registry.validate(&program, 8), Ok(()));
+    }
+}
@@ -0,0 +1,382 @@
+use super::opcode_set::OpcodeSet;
+use super::registry::OpcodeRegistry;
+use super::{OpcodeMapping, Opcodes, TestCase};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MappingSolution {
+    Complete(OpcodeMapping),
+    // More than one mapping fits the samples; holds the candidates left for each opcode number.
+    Underdetermined(Vec<OpcodeSet>),
+    // The opcode numbers that cannot be given any opcode.
+    Contradiction(Vec<i64>),
+}
+
+// Every mapping found by the full search, up to the requested limit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MappingSearch {
+    pub mappings : Vec<OpcodeMapping>,
+    pub exhaustive : bool,
+}
+
+impl MappingSearch {
+    pub fn is_unique(&self) -> bool {
+        self.exhaustive && self.mappings.len() == 1
+    }
+}
+
+fn empty_candidates(candidates : &[OpcodeSet]) -> Vec<i64> {
+    candidates
+        .iter()
+        .enumerate()
+        .filter(|(_, x)| x.is_empty())
+        .map(|(number, _)| number as i64)
+        .collect()
+}
+
+// Intersects the opcodes each sample allows per opcode number. Fails with the numbers that have
+// no candidate left, including numbers outside the opcode table.
+pub fn candidate_sets(test_cases : &[TestCase]) -> Result<Vec<OpcodeSet>, Vec<i64>> {
+    candidate_sets_in(OpcodeRegistry::builtin(), test_cases)
+}
+
+// As `candidate_sets`, with one opcode number per registered opcode.
+pub fn candidate_sets_in(registry : &OpcodeRegistry, test_cases : &[TestCase]) -> Result<Vec<OpcodeSet>, Vec<i64>> {
+
+    let mut solver = IncrementalSolver::new_in(registry);
+    test_cases.iter().for_each(|test_case| solver.add(test_case));
+
+    solver.candidates()
+}
+
+// Narrows the candidate sets one sample at a time, so samples can be streamed rather than collected.
+// Memory stays bounded by the opcode table, whatever the number of samples.
+pub struct IncrementalSolver<'a> {
+    registry : &'a OpcodeRegistry,
+    candidates : Vec<OpcodeSet>,
+    unknown_numbers : Vec<i64>,
+    samples : u64,
+}
+
+impl IncrementalSolver<'static> {
+    pub fn new() -> IncrementalSolver<'static> {
+        IncrementalSolver::new_in(OpcodeRegistry::builtin())
+    }
+}
+
+impl Default for IncrementalSolver<'static> {
+    fn default() -> Self {
+        IncrementalSolver::new()
+    }
+}
+
+impl<'a> IncrementalSolver<'a> {
+    pub fn new_in(registry : &'a OpcodeRegistry) -> IncrementalSolver<'a> {
+        IncrementalSolver { registry, candidates: vec![registry.all(); registry.len()], unknown_numbers: Vec::new(), samples: 0 }
+    }
+
+    pub fn add(&mut self, test_case : &TestCase) {
+        let number = test_case.instruction.0;
+        self.samples += 1;
+
+        match self.candidates.get_mut(number as usize).filter(|_| number >= 0) {
+            Some(candidate_set) => *candidate_set &= self.registry.matching_opcodes(test_case),
+            None => {
+                if let Err(idx) = self.unknown_numbers.binary_search(&number) {
+                    self.unknown_numbers.insert(idx, number);
+                }
+            },
+        }
+    }
+
+    // The number of samples added so far.
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    // The candidate sets so far, or the numbers left without a candidate as in `candidate_sets`.
+    pub fn candidates(&self) -> Result<Vec<OpcodeSet>, Vec<i64>> {
+        let mut contradictions = empty_candidates(&self.candidates);
+        contradictions.extend(self.unknown_numbers.iter().copied());
+
+        if contradictions.is_empty() {
+            Ok(self.candidates.clone())
+        }
+        else {
+            Err(contradictions)
+        }
+    }
+
+    // Solves for the samples added so far; more samples can still be added afterwards.
+    pub fn solve(&self) -> MappingSolution {
+        match self.candidates() {
+            Ok(candidates) => solve_candidates(candidates),
+            Err(contradictions) => MappingSolution::Contradiction(contradictions),
+        }
+    }
+}
+
+// Singleton elimination: a number with a single candidate claims it from every other number.
+fn propagate(candidates : &mut [OpcodeSet]) -> Result<(), Vec<i64>> {
+
+    let mut resolved = vec![false; candidates.len()];
+
+    loop {
+        let contradictions = empty_candidates(candidates);
+        if !contradictions.is_empty() {
+            return Err(contradictions);
+        }
+
+        let single_instructions : Vec<_> = candidates
+                                        .iter()
+                                        .enumerate()
+                                        .filter(|(i, x)| x.len() == 1 && !resolved[*i])
+                                        .map(|(i, x)|{
+                                            (i, x.first().unwrap())
+                                        })
+                                        .collect();
+
+        if single_instructions.is_empty() {
+            return Ok(());
+        }
+
+        single_instructions
+            .iter()
+            .for_each(|(idx, instruction)|{
+                resolved[*idx] = true;
+
+                candidates
+                        .iter_mut()
+                        .enumerate()
+                        .filter(|(i, _)| i != idx)
+                        .for_each(|(_, x)|{
+                            x.remove(*instruction);
+                        });
+            });
+    }
+}
+
+// Kuhn's augmenting path step for `number`.
+fn augment(number : usize, candidates : &[OpcodeSet], visited : &mut OpcodeSet, owner : &mut [Option<usize>]) -> bool {
+    for opcode in candidates[number] {
+        if !visited.insert(opcode) {
+            continue;
+        }
+
+        let free = match owner[opcode.index()] {
+            None => true,
+            Some(previous) => augment(previous, candidates, visited, owner),
+        };
+
+        if free {
+            owner[opcode.index()] = Some(number);
+            return true;
+        }
+    }
+
+    false
+}
+
+// The numbers left unassigned by a maximum bipartite matching between numbers and opcodes.
+fn unmatched_numbers(candidates : &[OpcodeSet]) -> Vec<i64> {
+    let mut owner = vec![None; OpcodeSet::CAPACITY];
+
+    (0..candidates.len())
+        .filter(|&number| !augment(number, candidates, &mut OpcodeSet::empty(), &mut owner))
+        .map(|number| number as i64)
+        .collect()
+}
+
+fn backtrack(candidates : &[OpcodeSet], assigned : &mut Vec<Option<Opcodes>>, used : OpcodeSet, limit : usize, found : &mut Vec<OpcodeMapping>) -> bool {
+    if found.len() >= limit {
+        return false;
+    }
+
+    // Branch on the unassigned number with the fewest remaining candidates.
+    let next = assigned
+        .iter()
+        .enumerate()
+        .filter(|(_, x)| x.is_none())
+        .map(|(number, _)| (candidates[number].difference(used).len(), number))
+        .min();
+
+    let number = match next {
+        Some((_, number)) => number,
+        None => {
+            found.push(OpcodeMapping::new(assigned.iter().map(|x| x.unwrap()).collect()));
+            return true;
+        },
+    };
+
+    for opcode in candidates[number].difference(used) {
+        assigned[number] = Some(opcode);
+        backtrack(candidates, assigned, used | OpcodeSet::single(opcode), limit, found);
+        assigned[number] = None;
+
+        if found.len() >= limit {
+            return false;
+        }
+    }
+
+    true
+}
+
+// Enumerates the one-to-one mappings that pick every number's opcode from its candidates.
+// Stops after `limit` mappings, in which case the search is not exhaustive.
+pub fn search_mappings(candidates : &[OpcodeSet], limit : usize) -> MappingSearch {
+    let mut found = Vec::new();
+    let exhaustive = backtrack(candidates, &mut vec![None; candidates.len()], OpcodeSet::empty(), limit, &mut found);
+
+    MappingSearch { mappings: found, exhaustive }
+}
+
+pub fn consistent_mappings(test_cases : &[TestCase], limit : usize) -> MappingSearch {
+    consistent_mappings_in(OpcodeRegistry::builtin(), test_cases, limit)
+}
+
+pub fn consistent_mappings_in(registry : &OpcodeRegistry, test_cases : &[TestCase], limit : usize) -> MappingSearch {
+    let mut candidates = match candidate_sets_in(registry, test_cases) {
+        Ok(candidates) => candidates,
+        Err(_) => return MappingSearch { mappings: Vec::new(), exhaustive: true },
+    };
+
+    if propagate(&mut candidates).is_err() || !unmatched_numbers(&candidates).is_empty() {
+        return MappingSearch { mappings: Vec::new(), exhaustive: true };
+    }
+
+    search_mappings(&candidates, limit)
+}
+
+pub fn solve_candidates(mut candidates : Vec<OpcodeSet>) -> MappingSolution {
+
+    if let Err(contradictions) = propagate(&mut candidates) {
+        return MappingSolution::Contradiction(contradictions);
+    }
+
+    if candidates.iter().all(|x| x.len() == 1) {
+        return MappingSolution::Complete(OpcodeMapping::new(candidates.iter().map(|x| x.first().unwrap()).collect()));
+    }
+
+    // Elimination stalled. A matching tells whether any mapping is left at all; the search then
+    // looks for a second one to decide whether it is unique.
+    let unmatched = unmatched_numbers(&candidates);
+    if !unmatched.is_empty() {
+        return MappingSolution::Contradiction(unmatched);
+    }
+
+    let mut search = search_mappings(&candidates, 2);
+
+    if search.is_unique() {
+        MappingSolution::Complete(search.mappings.remove(0))
+    }
+    else {
+        MappingSolution::Underdetermined(candidates)
+    }
+}
+
+pub fn solve_mapping(test_cases : &[TestCase]) -> MappingSolution {
+    solve_mapping_in(OpcodeRegistry::builtin(), test_cases)
+}
+
+pub fn solve_mapping_in(registry : &OpcodeRegistry, test_cases : &[TestCase]) -> MappingSolution {
+    match candidate_sets_in(registry, test_cases) {
+        Ok(candidates) => solve_candidates(candidates),
+        Err(contradictions) => MappingSolution::Contradiction(contradictions),
+    }
+}
+
+// Solves from a stream of samples, such as a `SampleReader`, stopping at the first error.
+pub fn solve_mapping_stream<E, I : IntoIterator<Item = Result<TestCase, E>>>(test_cases : I) -> Result<MappingSolution, E> {
+    let mut solver = IncrementalSolver::new();
+
+    for test_case in test_cases {
+        solver.add(&test_case?);
+    }
+
+    Ok(solver.solve())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::generator::PuzzleGenerator;
+    use super::super::parser::SampleReader;
+    use super::super::{InstructionSet, Register, POSSIBLE_INSTRUCTIONS};
+
+    fn test_case(before : &str, instruction : &str, after : &str) -> TestCase {
+        TestCase { before_register: Register::from(before), instruction: InstructionSet::from(instruction), after_register: Register::from(after) }
+    }
+
+    #[test]
+    fn solve_mapping_underdetermined_without_samples() {
+
+        match solve_mapping(&[]) {
+            MappingSolution::Underdetermined(candidates) => {
+                assert_eq!(candidates.len(), 16);
+                assert!(candidates.iter().all(|x| x.len() == 16));
+            },
+            other => panic!("Unexpected solution {:?}", other),
+        }
+
+        let search = consistent_mappings(&[], 3);
+        assert_eq!(search.mappings.len(), 3);
+        assert!(!search.exhaustive);
+    }
+
+    #[test]
+    fn solve_mapping_contradiction() {
+
+        // Opcode 3 cannot turn [1, 2, 3, 4] into [9, 9, 9, 9] whatever it is, and opcode 20 does not exist.
+        let test_cases = vec![
+            test_case("1, 2, 3, 4", "3 0 1 2", "9, 9, 9, 9"),
+            test_case("1, 2, 3, 4", "20 0 1 2", "1, 2, 3, 4"),
+        ];
+
+        assert_eq!(solve_mapping(&test_cases), MappingSolution::Contradiction(vec![3, 20]));
+    }
+
+    #[test]
+    fn search_confirms_unique_mapping() {
+
+        let mut candidates : Vec<_> = POSSIBLE_INSTRUCTIONS.iter().map(|x| OpcodeSet::single(*x)).collect();
+        candidates[0] = OpcodeSet::single(Opcodes::addr) | OpcodeSet::single(Opcodes::eqrr);
+
+        let search = search_mappings(&candidates, 10);
+        assert!(search.is_unique());
+        assert_eq!(search.mappings[0], OpcodeMapping::new(POSSIBLE_INSTRUCTIONS.to_vec()));
+        assert_eq!(solve_candidates(candidates), MappingSolution::Complete(OpcodeMapping::new(POSSIBLE_INSTRUCTIONS.to_vec())));
+    }
+
+    #[test]
+    fn search_finds_every_mapping() {
+
+        let mut candidates : Vec<_> = POSSIBLE_INSTRUCTIONS.iter().map(|x| OpcodeSet::single(*x)).collect();
+        candidates[0] = OpcodeSet::single(Opcodes::addr) | OpcodeSet::single(Opcodes::addi);
+        candidates[1] = OpcodeSet::single(Opcodes::addr) | OpcodeSet::single(Opcodes::addi);
+
+        let search = search_mappings(&candidates, 10);
+        assert_eq!(search.mappings.len(), 2);
+        assert!(search.exhaustive && !search.is_unique());
+        assert!(matches!(solve_candidates(candidates.clone()), MappingSolution::Underdetermined(_)));
+
+        candidates[2] = OpcodeSet::single(Opcodes::addr) | OpcodeSet::single(Opcodes::addi);
+        assert_eq!(solve_candidates(candidates), MappingSolution::Contradiction(vec![2]));
+    }
+
+    #[test]
+    fn incremental_solver_matches_batch() {
+
+        let mut generator = PuzzleGenerator::random(23);
+        let test_cases : Vec<TestCase> = (0..800).map(|_| generator.sample()).collect();
+
+        let mut solver = IncrementalSolver::new();
+        test_cases.iter().for_each(|test_case| solver.add(test_case));
+
+        assert_eq!(solver.samples(), 800);
+        assert_eq!(solver.candidates(), candidate_sets(&test_cases));
+        assert_eq!(solver.solve(), MappingSolution::Complete(generator.mapping().clone()));
+
+        let input = generator.input(800, 0);
+        let samples = SampleReader::new(input.as_bytes());
+        assert_eq!(solve_mapping_stream(samples).unwrap(), MappingSolution::Complete(generator.mapping().clone()));
+    }
+}
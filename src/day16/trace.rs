@@ -0,0 +1,184 @@
+use super::executor::{Machine, VmError};
+use super::registry::OpcodeRegistry;
+use super::{Instruction, Overflow, Program, Register};
+use std::io::{self, Write};
+
+// Serializes as the objects `to_json` writes, so the lines of `write_json_lines` read back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(into = "TraceRecord", try_from = "TraceRecord"))]
+pub struct TraceStep {
+    pub index : usize,
+    pub instruction : Instruction,
+    pub before : Register,
+    pub after : Register,
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct TraceRecord {
+    index : usize,
+    opcode : String,
+    operands : [i64; 3],
+    before : Register,
+    after : Register,
+}
+
+#[cfg(feature = "serde")]
+impl From<TraceStep> for TraceRecord {
+    fn from(step : TraceStep) -> Self {
+        let Instruction { opcode, a, b, c } = step.instruction;
+        TraceRecord { index: step.index, opcode: opcode.to_string(), operands: [a, b, c], before: step.before, after: step.after }
+    }
+}
+
+// Custom opcodes read back only under the `custom{id}` names they have outside a registry.
+#[cfg(feature = "serde")]
+impl std::convert::TryFrom<TraceRecord> for TraceStep {
+    type Error = String;
+
+    fn try_from(record : TraceRecord) -> Result<Self, String> {
+        let opcode = super::Opcodes::from_name(&record.opcode)
+            .or_else(|| record.opcode.strip_prefix("custom")?.parse().ok().map(super::Opcodes::Custom))
+            .ok_or_else(|| format!("unknown opcode `{}`", record.opcode))?;
+
+        let [a, b, c] = record.operands;
+        Ok(TraceStep { index: record.index, instruction: Instruction { opcode, a, b, c }, before: record.before, after: record.after })
+    }
+}
+
+impl TraceStep {
+    pub fn to_json(&self) -> String {
+        self.to_json_in(OpcodeRegistry::builtin())
+    }
+
+    // As `to_json`, naming custom opcodes as the registry does.
+    pub fn to_json_in(&self, registry : &OpcodeRegistry) -> String {
+        format!("{{\"index\":{},\"opcode\":\"{}\",\"operands\":[{},{},{}],\"before\":{},\"after\":{}}}",
+                self.index,
+                registry.display_name(self.instruction.opcode),
+                self.instruction.a, self.instruction.b, self.instruction.c,
+                json_array(&self.before),
+                json_array(&self.after))
+    }
+}
+
+fn json_array(register : &Register) -> String {
+    let values : Vec<String> = register.values().iter().map(|x| x.to_string()).collect();
+    format!("[{}]", values.join(","))
+}
+
+// Steps through a program, yielding one record per executed instruction. Iteration ends when the
+// program halts or faults; the fault, if any, is available afterwards.
+pub struct Trace<'a> {
+    machine : Machine<'a>,
+    fault : Option<VmError>,
+}
+
+impl<'a> Trace<'a> {
+    pub fn new(program : &'a Program, register : Register) -> Trace<'a> {
+        Trace { machine: Machine::new(program, register), fault: None }
+    }
+
+    // Arithmetic is checked unless chosen otherwise here, as for `Machine`.
+    pub fn with_overflow(mut self, overflow : Overflow) -> Trace<'a> {
+        self.machine = self.machine.with_overflow(overflow);
+        self
+    }
+
+    // Needed to run, and name, custom opcodes.
+    pub fn with_registry(mut self, registry : &'a OpcodeRegistry) -> Trace<'a> {
+        self.machine = self.machine.with_registry(registry);
+        self
+    }
+
+    pub fn fault(&self) -> Option<&VmError> {
+        self.fault.as_ref()
+    }
+
+    pub fn machine(&self) -> &Machine<'a> {
+        &self.machine
+    }
+}
+
+impl<'a> Iterator for Trace<'a> {
+    type Item = TraceStep;
+
+    fn next(&mut self) -> Option<TraceStep> {
+        if self.fault.is_some() {
+            return None;
+        }
+
+        let index = self.machine.ip() as usize;
+        let instruction = *self.machine.current_instruction()?;
+        let before = self.machine.register().clone();
+
+        match self.machine.step() {
+            Ok(_) => Some(TraceStep { index, instruction, before, after: self.machine.register().clone() }),
+            Err(error) => {
+                self.fault = Some(error);
+                None
+            },
+        }
+    }
+}
+
+// Writes one JSON object per step. A fault is written as a final `{"error": ...}` line.
+pub fn write_json_lines<W : Write>(trace : &mut Trace, out : &mut W) -> io::Result<()> {
+    let registry = trace.machine().registry();
+    for step in trace.by_ref() {
+        writeln!(out, "{}", step.to_json_in(registry))?;
+    }
+
+    if let Some(fault) = trace.fault() {
+        writeln!(out, "{{\"error\":\"{}\"}}", fault.to_string().replace('\\', "\\\\").replace('"', "\\\""))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::assembler::assemble;
+
+    #[test]
+    fn trace_records_each_step() {
+
+        let program = assemble("seti 3 0 1\nmuli 1 2 0").unwrap();
+        let steps : Vec<TraceStep> = Trace::new(&program, Register::new(2)).collect();
+
+        assert_eq!(steps, vec![
+            TraceStep { index: 0, instruction: program.instructions[0], before: Register::from("0, 0"), after: Register::from("0, 3") },
+            TraceStep { index: 1, instruction: program.instructions[1], before: Register::from("0, 3"), after: Register::from("6, 3") },
+        ]);
+    }
+
+    #[test]
+    fn trace_json_lines() {
+
+        let program = assemble("seti 3 0 1\naddr 1 5 0").unwrap();
+        let mut trace = Trace::new(&program, Register::new(2));
+        let mut out = Vec::new();
+
+        write_json_lines(&mut trace, &mut out).unwrap();
+
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "{\"index\":0,\"opcode\":\"seti\",\"operands\":[3,0,1],\"before\":[0,0],\"after\":[0,3]}\n\
+                    {\"error\":\"instruction 1 (`addr 1 5 0`) uses register 5, which does not exist\"}\n");
+        assert!(trace.fault().is_some());
+    }
+
+    #[test]
+    fn trace_follows_overflow_mode() {
+
+        let program = assemble("seti 2 0 0\nmuli 0 4611686018427387904 0").unwrap();
+
+        let mut trace = Trace::new(&program, Register::new(1));
+        assert_eq!(trace.by_ref().count(), 1);
+        assert!(trace.fault().is_some());
+
+        let steps : Vec<TraceStep> = Trace::new(&program, Register::new(1)).with_overflow(Overflow::Wrapping).collect();
+        assert_eq!(steps[1].after, Register(vec![i64::MIN]));
+    }
+}
@@ -0,0 +1,231 @@
+use super::{ApplyError, Instruction, InstructionSet, Mode, OpcodeMapping, Opcodes, Overflow, Register};
+use std::error::Error;
+use std::fmt;
+
+// A register operand, known to exist in the register file the instruction was decoded for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Reg(usize);
+
+impl Reg {
+    pub fn new(register : i64, register_count : usize) -> Option<Reg> {
+        if register >= 0 && (register as usize) < register_count {
+            Some(Reg(register as usize))
+        }
+        else {
+            None
+        }
+    }
+
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+impl fmt::Display for Reg {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "r{}", self.0)
+    }
+
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    A,
+    B,
+    C,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeErrorKind {
+    UnmappedOpcode(i64),
+    // Custom opcodes have no typed form; run them through their registry instead.
+    CustomOpcode(Opcodes),
+    BadRegister { operand : Operand, register : i64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeError {
+    pub index : usize,
+    pub kind : DecodeErrorKind,
+}
+
+impl fmt::Display for DecodeErrorKind {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeErrorKind::UnmappedOpcode(number) => write!(f, "opcode number {} is not mapped", number),
+            DecodeErrorKind::CustomOpcode(opcode) => write!(f, "opcode {} is custom and has no typed form", opcode),
+            DecodeErrorKind::BadRegister { operand, register } => write!(f, "operand {:?} names register {}, which does not exist", operand, register),
+        }
+    }
+
+}
+
+impl fmt::Display for DecodeError {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "instruction {}: {}", self.index, self.kind)
+    }
+
+}
+
+impl Error for DecodeErrorKind {}
+impl Error for DecodeError {}
+
+// A built-in instruction with its operands sorted into registers and immediates. Operands an
+// opcode ignores (`b` of setr and seti) are dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TypedInstruction {
+    Addr { a : Reg, b : Reg, c : Reg },
+    Addi { a : Reg, b : i64, c : Reg },
+    Mulr { a : Reg, b : Reg, c : Reg },
+    Muli { a : Reg, b : i64, c : Reg },
+    Banr { a : Reg, b : Reg, c : Reg },
+    Bani { a : Reg, b : i64, c : Reg },
+    Borr { a : Reg, b : Reg, c : Reg },
+    Bori { a : Reg, b : i64, c : Reg },
+    Setr { a : Reg, c : Reg },
+    Seti { a : i64, c : Reg },
+    Gtir { a : i64, b : Reg, c : Reg },
+    Gtri { a : Reg, b : i64, c : Reg },
+    Gtrr { a : Reg, b : Reg, c : Reg },
+    Eqir { a : i64, b : Reg, c : Reg },
+    Eqri { a : Reg, b : i64, c : Reg },
+    Eqrr { a : Reg, b : Reg, c : Reg },
+}
+
+impl TypedInstruction {
+    pub fn decode(instruction : &Instruction, register_count : usize) -> Result<TypedInstruction, DecodeErrorKind> {
+        if let Opcodes::Custom(_) = instruction.opcode {
+            return Err(DecodeErrorKind::CustomOpcode(instruction.opcode));
+        }
+
+        let (a, b, c) = (instruction.a, instruction.b, instruction.c);
+        let (mode_a, mode_b) = instruction.opcode.modes();
+
+        [(Operand::C, Mode::Register, c), (Operand::A, mode_a, a), (Operand::B, mode_b, b)]
+            .iter()
+            .find(|(_, mode, register)| mode.is_register() && Reg::new(*register, register_count).is_none())
+            .map_or(Ok(()), |&(operand, _, register)| Err(DecodeErrorKind::BadRegister { operand, register }))?;
+
+        // The modes have vouched for every register operand, so below they only need sorting.
+        let reg = |register : i64| Reg(register as usize);
+        let c = reg(c);
+
+        Ok(match instruction.opcode {
+            Opcodes::addr => TypedInstruction::Addr { a: reg(a), b: reg(b), c },
+            Opcodes::addi => TypedInstruction::Addi { a: reg(a), b, c },
+            Opcodes::mulr => TypedInstruction::Mulr { a: reg(a), b: reg(b), c },
+            Opcodes::muli => TypedInstruction::Muli { a: reg(a), b, c },
+            Opcodes::banr => TypedInstruction::Banr { a: reg(a), b: reg(b), c },
+            Opcodes::bani => TypedInstruction::Bani { a: reg(a), b, c },
+            Opcodes::borr => TypedInstruction::Borr { a: reg(a), b: reg(b), c },
+            Opcodes::bori => TypedInstruction::Bori { a: reg(a), b, c },
+            Opcodes::setr => TypedInstruction::Setr { a: reg(a), c },
+            Opcodes::seti => TypedInstruction::Seti { a, c },
+            Opcodes::gtir => TypedInstruction::Gtir { a, b: reg(b), c },
+            Opcodes::gtri => TypedInstruction::Gtri { a: reg(a), b, c },
+            Opcodes::gtrr => TypedInstruction::Gtrr { a: reg(a), b: reg(b), c },
+            Opcodes::eqir => TypedInstruction::Eqir { a, b: reg(b), c },
+            Opcodes::eqri => TypedInstruction::Eqri { a: reg(a), b, c },
+            Opcodes::eqrr => TypedInstruction::Eqrr { a: reg(a), b: reg(b), c },
+            Opcodes::Custom(_) => unreachable!("custom opcodes are rejected above"),
+        })
+    }
+
+    // Back to the untyped form; dropped operands come back as 0.
+    pub fn to_instruction(self) -> Instruction {
+        let (opcode, a, b, c) = match self {
+            TypedInstruction::Addr { a, b, c } => (Opcodes::addr, a.0 as i64, b.0 as i64, c),
+            TypedInstruction::Addi { a, b, c } => (Opcodes::addi, a.0 as i64, b, c),
+            TypedInstruction::Mulr { a, b, c } => (Opcodes::mulr, a.0 as i64, b.0 as i64, c),
+            TypedInstruction::Muli { a, b, c } => (Opcodes::muli, a.0 as i64, b, c),
+            TypedInstruction::Banr { a, b, c } => (Opcodes::banr, a.0 as i64, b.0 as i64, c),
+            TypedInstruction::Bani { a, b, c } => (Opcodes::bani, a.0 as i64, b, c),
+            TypedInstruction::Borr { a, b, c } => (Opcodes::borr, a.0 as i64, b.0 as i64, c),
+            TypedInstruction::Bori { a, b, c } => (Opcodes::bori, a.0 as i64, b, c),
+            TypedInstruction::Setr { a, c } => (Opcodes::setr, a.0 as i64, 0, c),
+            TypedInstruction::Seti { a, c } => (Opcodes::seti, a, 0, c),
+            TypedInstruction::Gtir { a, b, c } => (Opcodes::gtir, a, b.0 as i64, c),
+            TypedInstruction::Gtri { a, b, c } => (Opcodes::gtri, a.0 as i64, b, c),
+            TypedInstruction::Gtrr { a, b, c } => (Opcodes::gtrr, a.0 as i64, b.0 as i64, c),
+            TypedInstruction::Eqir { a, b, c } => (Opcodes::eqir, a, b.0 as i64, c),
+            TypedInstruction::Eqri { a, b, c } => (Opcodes::eqri, a.0 as i64, b, c),
+            TypedInstruction::Eqrr { a, b, c } => (Opcodes::eqrr, a.0 as i64, b.0 as i64, c),
+        };
+
+        Instruction { opcode, a, b, c: c.0 as i64 }
+    }
+
+    pub fn opcode(self) -> Opcodes {
+        self.to_instruction().opcode
+    }
+
+    pub fn apply(self, register : &mut Register, overflow : Overflow) -> Result<(), ApplyError> {
+        self.to_instruction().apply_with(register, overflow)
+    }
+}
+
+impl fmt::Display for TypedInstruction {
+
+    // Registers are written `rN` so they stand apart from immediates, e.g. `gtri r1 5 r2`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let opcode = self.opcode();
+        match *self {
+            TypedInstruction::Addr { a, b, c } | TypedInstruction::Mulr { a, b, c } | TypedInstruction::Banr { a, b, c }
+                | TypedInstruction::Borr { a, b, c } | TypedInstruction::Gtrr { a, b, c } | TypedInstruction::Eqrr { a, b, c } => write!(f, "{} {} {} {}", opcode, a, b, c),
+            TypedInstruction::Addi { a, b, c } | TypedInstruction::Muli { a, b, c } | TypedInstruction::Bani { a, b, c }
+                | TypedInstruction::Bori { a, b, c } | TypedInstruction::Gtri { a, b, c } | TypedInstruction::Eqri { a, b, c } => write!(f, "{} {} {} {}", opcode, a, b, c),
+            TypedInstruction::Gtir { a, b, c } | TypedInstruction::Eqir { a, b, c } => write!(f, "{} {} {} {}", opcode, a, b, c),
+            TypedInstruction::Setr { a, c } => write!(f, "{} {} {}", opcode, a, c),
+            TypedInstruction::Seti { a, c } => write!(f, "{} {} {}", opcode, a, c),
+        }
+    }
+
+}
+
+impl OpcodeMapping {
+    pub fn decode_typed(&self, instruction : &InstructionSet, register_count : usize) -> Result<TypedInstruction, DecodeErrorKind> {
+        let decoded = self.decode(instruction).ok_or(DecodeErrorKind::UnmappedOpcode(instruction.0))?;
+        TypedInstruction::decode(&decoded, register_count)
+    }
+
+    pub fn decode_typed_program(&self, program : &[InstructionSet], register_count : usize) -> Result<Vec<TypedInstruction>, DecodeError> {
+        program
+            .iter()
+            .enumerate()
+            .map(|(index, instruction)| self.decode_typed(instruction, register_count).map_err(|kind| DecodeError { index, kind }))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::POSSIBLE_INSTRUCTIONS;
+
+    #[test]
+    fn decode_sorts_operands_by_kind() {
+
+        let mapping = OpcodeMapping::new(POSSIBLE_INSTRUCTIONS.to_vec());
+        let program = vec![InstructionSet(10, 7, 2, 1), InstructionSet(9, -3, 99, 0), InstructionSet(0, 1, 2, 3)];
+
+        let typed = mapping.decode_typed_program(&program, 4).unwrap();
+        assert_eq!(typed[0], TypedInstruction::Gtir { a: 7, b: Reg(2), c: Reg(1) });
+        assert_eq!(typed[1], TypedInstruction::Seti { a: -3, c: Reg(0) });
+        assert_eq!(typed[0].to_string(), "gtir 7 r2 r1");
+        assert_eq!(typed[1].to_string(), "seti -3 r0");
+
+        let mut register = Register::from("0, 5, 6, 0");
+        typed[2].apply(&mut register, Overflow::Checked).unwrap();
+        assert_eq!(register, Register::from("0, 5, 6, 11"));
+
+        assert_eq!(mapping.decode_typed_program(&program, 3), Err(DecodeError { index: 2, kind: DecodeErrorKind::BadRegister { operand: Operand::C, register: 3 } }));
+        assert_eq!(mapping.decode_typed(&InstructionSet(11, 7, 2, 1), 4), Err(DecodeErrorKind::BadRegister { operand: Operand::A, register: 7 }));
+        assert_eq!(mapping.decode_typed(&InstructionSet(16, 0, 0, 0), 4), Err(DecodeErrorKind::UnmappedOpcode(16)));
+
+        let custom = Instruction { opcode: Opcodes::Custom(0), a: 0, b: 1, c: 2 };
+        assert_eq!(TypedInstruction::decode(&custom, 4), Err(DecodeErrorKind::CustomOpcode(Opcodes::Custom(0))));
+    }
+}
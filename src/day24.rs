@@ -1,3 +1,6 @@
+// Kept as originally written; these lints flag only its style.
+#![allow(clippy::println_empty_string, clippy::len_zero, clippy::redundant_field_names, clippy::needless_borrow, clippy::ptr_arg)]
+
 use regex::{Regex};
 use std::collections::HashMap;
 use std::cmp::Reverse;